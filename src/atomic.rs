@@ -0,0 +1,101 @@
+//! Crash-safe replacement of files.
+//!
+//! New contents go to a uniquely named temporary file in the same directory
+//! as the target, are flushed to disk, and are then renamed over the target.
+//! Renames within one directory are atomic, so readers (and Obsidian) only
+//! ever see the old file or the complete new one.
+
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use color_eyre::eyre::{Result, eyre};
+
+/// Replaces the file at `path` with whatever `write` produces. The existing
+/// file's permissions are kept; new files are created readable by everyone
+/// and writable by the owner. If `write` or any later step fails, the
+/// temporary file is removed and `path` is left untouched.
+pub fn write_atomic<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> Result<()>,
+{
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| eyre!("{} is not a file path", path.display()))?;
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+
+    // dropping a NamedTempFile deletes it, which covers every early return
+    let mut tmp = tempfile::Builder::new()
+        .prefix(&format!(".{}.", file_name.to_string_lossy()))
+        .suffix(".tmp")
+        .tempfile_in(dir)?;
+    {
+        let mut out = BufWriter::new(tmp.as_file_mut());
+        write(&mut out)?;
+        out.flush()?;
+    }
+    match fs::metadata(path) {
+        Ok(metadata) => tmp.as_file().set_permissions(metadata.permissions())?,
+        #[cfg(unix)]
+        Err(_) => {
+            use std::os::unix::fs::PermissionsExt;
+            // temporary files are private by default, plugin files are not
+            tmp.as_file()
+                .set_permissions(fs::Permissions::from_mode(0o644))?;
+        }
+        #[cfg(not(unix))]
+        Err(_) => {}
+    }
+    tmp.as_file().sync_all()?;
+    tmp.persist(path).map_err(|e| e.error)?;
+
+    // make the rename itself durable
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failed_write_leaves_target_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("main.js");
+        fs::write(&target, b"old").unwrap();
+
+        let result = write_atomic(&target, |out| {
+            out.write_all(b"half of the new")?;
+            Err(eyre!("interrupted"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        write_atomic(&target, |out| Ok(out.write_all(b"new")?)).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions_are_preserved() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("run.sh");
+        fs::write(&target, b"old").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o751)).unwrap();
+
+        write_atomic(&target, |out| Ok(out.write_all(b"new")?)).unwrap();
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o751);
+    }
+}
@@ -0,0 +1,82 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use obsidian_syncer::plan::DeletionGuard;
+
+/// Keeps the community plugins of all your Obsidian vaults in sync.
+#[derive(Debug, Clone, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Work out and log what every sync would write or delete, without
+    /// changing any vault.
+    #[arg(long, env = "OBSIDIAN_SYNCER_DRY_RUN")]
+    pub dry_run: bool,
+
+    /// Most plugin files a single sync may delete from a vault.
+    #[arg(long, env = "OBSIDIAN_SYNCER_MAX_DELETIONS", default_value_t = DeletionGuard::default().max_files)]
+    pub max_deletions: usize,
+
+    /// Largest share, in percent, of a vault's plugin files that a single sync
+    /// may delete.
+    #[arg(long, env = "OBSIDIAN_SYNCER_MAX_DELETION_PERCENT", default_value_t = DeletionGuard::default().max_percent)]
+    pub max_deletion_percent: f64,
+
+    /// Allow syncing from a vault whose plugins folder is empty, which deletes
+    /// every plugin file in the other vaults.
+    #[arg(long)]
+    pub allow_empty_source: bool,
+
+    /// Go past the deletion limits without asking for confirmation.
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// List snapshots, conflicts, or the syncs in a vault's trash.
+    #[command(subcommand)]
+    List(List),
+    /// Put back the files a sync deleted or overwrote in a vault.
+    Restore { vault: PathBuf, sync_id: String },
+    /// Write the other vault's copy of a file left in conflict in `vault` to
+    /// `out`. `path` is relative to the vault, as `list conflicts` shows it.
+    Extract {
+        vault: PathBuf,
+        path: PathBuf,
+        out: PathBuf,
+    },
+    /// Put the plugins of a vault, or of every vault with --all, back the way
+    /// they were before the sync that took the snapshot.
+    Rollback {
+        snapshot: String,
+        #[arg(required_unless_present = "all")]
+        vault: Option<PathBuf>,
+        /// Roll back every vault the snapshot was taken of.
+        #[arg(long, conflicts_with = "vault")]
+        all: bool,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum List {
+    /// List the snapshots taken before each sync.
+    Snapshots,
+    /// List plugin files that were changed in two vaults and left unsynced.
+    Conflicts,
+    /// List the syncs whose deleted or overwritten files are in a vault's
+    /// trash.
+    Trash { vault: PathBuf },
+}
+
+impl Cli {
+    pub fn deletion_guard(&self) -> DeletionGuard {
+        DeletionGuard {
+            max_files: self.max_deletions,
+            max_percent: self.max_deletion_percent,
+            refuse_empty_source: !self.allow_empty_source,
+        }
+    }
+}
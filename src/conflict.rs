@@ -0,0 +1,225 @@
+//! Files that were changed in two vaults since they were last synced.
+//!
+//! Syncing leaves the destination's copy of a conflicting file alone, or
+//! for plugin settings merges everything but the clashing keys, and keeps
+//! the source's copy in the [`ObjectStore`], so neither edit is lost.
+//! The open conflicts of each vault are listed in `conflicts/<vault id>.json`
+//! in the data dir until a later sync brings the file back in line.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use color_eyre::eyre::{Result, eyre};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::atomic::write_atomic;
+use crate::logging::get_data_dir;
+use crate::objects::ObjectStore;
+use crate::structs::vault_id;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conflict {
+    /// The vault whose copy was kept.
+    pub vault: PathBuf,
+    /// Path relative to the vault.
+    pub path: PathBuf,
+    /// The vault whose copy was not synced.
+    pub origin: PathBuf,
+    /// Hex hashes of the contents, the keys in the [`ObjectStore`] for
+    /// `theirs`. `base` is `None` when our copy was never synced and
+    /// `theirs` is `None` when the origin deleted the file.
+    pub base: Option<String>,
+    pub ours: String,
+    pub theirs: Option<String>,
+    /// JSON pointers of the clashing keys when the file is plugin settings
+    /// and everything else was merged.
+    #[serde(default)]
+    pub keys: Vec<String>,
+    pub detected: SystemTime,
+}
+
+impl Conflict {
+    /// A conflict over `rel` in `vault` whose other copy came from `origin`.
+    pub fn new(
+        vault: &Path,
+        rel: &Path,
+        origin: &Path,
+        base: Option<&[u8; 32]>,
+        ours: &[u8; 32],
+    ) -> Self {
+        Self {
+            vault: vault.to_path_buf(),
+            path: rel.to_path_buf(),
+            origin: origin.to_path_buf(),
+            base: base.map(hex),
+            ours: hex(ours),
+            theirs: None,
+            keys: Vec::new(),
+            detected: SystemTime::now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConflictStore {
+    dir: PathBuf,
+    objects: ObjectStore,
+}
+
+impl Default for ConflictStore {
+    fn default() -> Self {
+        Self::new(get_data_dir().join("conflicts"), ObjectStore::default())
+    }
+}
+
+impl ConflictStore {
+    pub fn new(dir: impl Into<PathBuf>, objects: ObjectStore) -> Self {
+        Self {
+            dir: dir.into(),
+            objects,
+        }
+    }
+
+    /// Records `conflict`, storing `theirs`, the origin's copy, if it still
+    /// exists.
+    pub fn record(&self, mut conflict: Conflict, theirs: Option<&Path>) -> Result<Conflict> {
+        if let Some(path) = theirs {
+            conflict.theirs = Some(hex(&self.objects.insert_file(path)?));
+        }
+        let mut open = self.load(&conflict.vault)?;
+        open.insert(conflict.path.clone(), conflict.clone());
+        self.save(&conflict.vault, &open)?;
+        debug!(vault = ?conflict.vault, file = ?conflict.path, "Recorded conflict");
+        Ok(conflict)
+    }
+
+    /// Forgets the conflicts over `rels` in `vault`, if there are any.
+    pub fn resolve<'a>(
+        &self,
+        vault: &Path,
+        rels: impl IntoIterator<Item = &'a Path>,
+    ) -> Result<()> {
+        let mut open = self.load(vault)?;
+        let before = open.len();
+        for rel in rels {
+            open.remove(rel);
+        }
+        if open.len() != before {
+            self.save(vault, &open)?;
+        }
+        Ok(())
+    }
+
+    /// The open conflicts of `vault`, by path relative to the vault.
+    pub fn load(&self, vault: &Path) -> Result<BTreeMap<PathBuf, Conflict>> {
+        match fs::read(self.path_of(vault)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the origin's copy of the file in the open conflict over `rel` in
+    /// `vault` to `target`.
+    pub fn extract_theirs(&self, vault: &Path, rel: &Path, target: &Path) -> Result<()> {
+        let open = self.load(vault)?;
+        let conflict = open
+            .get(rel)
+            .ok_or_else(|| eyre!("no conflict over {} in {}", rel.display(), vault.display()))?;
+        let theirs = conflict
+            .theirs
+            .as_ref()
+            .ok_or_else(|| eyre!("{} deleted {}", conflict.origin.display(), rel.display()))?;
+        self.objects
+            .restore_to(blake3::Hash::from_hex(theirs)?.as_bytes(), target)
+    }
+
+    /// The open conflicts of every vault.
+    pub fn list(&self) -> Result<Vec<Conflict>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut conflicts = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            match serde_json::from_slice::<BTreeMap<PathBuf, Conflict>>(&fs::read(&path)?) {
+                Ok(open) => conflicts.extend(open.into_values()),
+                Err(e) => warn!(conflicts = ?path, "Ignoring unreadable conflicts: {}", e),
+            }
+        }
+        conflicts.sort_by(|a, b| a.vault.cmp(&b.vault).then(a.path.cmp(&b.path)));
+        Ok(conflicts)
+    }
+
+    fn save(&self, vault: &Path, open: &BTreeMap<PathBuf, Conflict>) -> Result<()> {
+        let path = self.path_of(vault);
+        if open.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        write_atomic(&path, |out| {
+            serde_json::to_writer_pretty(out, open)?;
+            Ok(())
+        })
+    }
+
+    fn path_of(&self, vault: &Path) -> PathBuf {
+        let id = vault_id(vault);
+        self.dir.join(format!("{id:016x}.json"))
+    }
+}
+
+fn hex(hash: &[u8; 32]) -> String {
+    blake3::Hash::from(*hash).to_hex().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conflicts_keep_theirs_until_resolved() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::new(dir.path().join("objects"));
+        let store = ConflictStore::new(dir.path().join("conflicts"), objects.clone());
+        let theirs = dir.path().join("data.json");
+        fs::write(&theirs, r#"{"theme":"dark"}"#).unwrap();
+        let vault = Path::new("/vaults/work");
+        let rel = Path::new(".obsidian/plugins/calendar/data.json");
+
+        let conflict = Conflict::new(
+            vault,
+            rel,
+            Path::new("/vaults/main"),
+            Some(&[1; 32]),
+            &[2; 32],
+        );
+        let conflict = store.record(conflict, Some(&theirs)).unwrap();
+        let stored = *blake3::Hash::from_hex(conflict.theirs.as_ref().unwrap())
+            .unwrap()
+            .as_bytes();
+        assert_eq!(objects.read(&stored).unwrap(), br#"{"theme":"dark"}"#);
+        assert_eq!(store.list().unwrap(), vec![conflict]);
+        let out = dir.path().join("theirs.json");
+        store.extract_theirs(vault, rel, &out).unwrap();
+        assert_eq!(fs::read(&out).unwrap(), br#"{"theme":"dark"}"#);
+        assert!(
+            store
+                .extract_theirs(vault, Path::new("other.json"), &out)
+                .is_err()
+        );
+
+        store.resolve(vault, [rel]).unwrap();
+        assert!(store.load(vault).unwrap().is_empty());
+        assert!(store.list().unwrap().is_empty());
+    }
+}
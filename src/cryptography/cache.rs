@@ -0,0 +1,186 @@
+//! On-disk cache of [`Signature`] tables.
+//!
+//! Each cached file gets one JSON entry named after a hash of its path. The
+//! entry remembers the size, modification time and inode the signature was
+//! built from, and is only reused while all of them still match.
+
+use std::{
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::chunking::ChunkingStrategy;
+use super::hashing::StrongHashKind;
+use super::signatures::{BlockSizeBounds, Signature};
+use crate::atomic::write_atomic;
+use crate::logging::get_data_dir;
+
+/// Identifies one version of a file without reading it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FileKey {
+    pub(crate) path: PathBuf,
+    pub(crate) size: u64,
+    pub(crate) mtime: SystemTime,
+    pub(crate) inode: u64,
+}
+
+impl FileKey {
+    pub(crate) fn for_path(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            mtime: metadata.modified()?,
+            inode,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    key: FileKey,
+    signature: Signature,
+}
+
+#[derive(Debug, Clone)]
+pub struct SignatureCache {
+    dir: PathBuf,
+    strategy: ChunkingStrategy,
+    bounds: BlockSizeBounds,
+    hash: StrongHashKind,
+}
+
+impl Default for SignatureCache {
+    fn default() -> Self {
+        Self::new(get_data_dir().join("signatures"))
+    }
+}
+
+impl SignatureCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            strategy: ChunkingStrategy::default(),
+            bounds: BlockSizeBounds::default(),
+            hash: StrongHashKind::default(),
+        }
+    }
+
+    /// Chunking strategy for newly built signatures. Cached signatures built
+    /// with a different strategy are rebuilt.
+    pub fn with_strategy(mut self, strategy: ChunkingStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Block size bounds for newly built signatures. Cached signatures whose
+    /// block size does not match these bounds are rebuilt.
+    pub fn with_bounds(mut self, bounds: BlockSizeBounds) -> Self {
+        self.bounds = bounds;
+        self
+    }
+
+    /// Strong hash for newly built signatures. Cached signatures built with a
+    /// different hash are rebuilt.
+    pub fn with_hash(mut self, hash: StrongHashKind) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// Returns the signature of the file at `path`, only rehashing it when its
+    /// metadata changed since the cached signature was built.
+    pub fn signature_for(&self, path: &Path) -> Result<Signature> {
+        let path = fs::canonicalize(path)?;
+        let key = FileKey::for_path(&path)?;
+        let entry_path = self.entry_path(&path);
+
+        if let Some(entry) = self.load(&entry_path)
+            && entry.key == key
+            && entry.signature.strategy() == self.strategy
+            && entry.signature.hash_kind() == self.hash
+            && entry.signature.block_size() == self.bounds.block_size_for(key.size)
+        {
+            debug!(path = ?path, "Using cached signature");
+            return Ok(entry.signature);
+        }
+
+        debug!(path = ?path, "Building signature");
+        let mut signature = Signature::with_strategy(self.strategy)
+            .with_bounds(self.bounds)
+            .with_hash(self.hash);
+        signature.build_from(BufReader::new(fs::File::open(&path)?), key.size)?;
+        let entry = CacheEntry { key, signature };
+        if let Err(e) = self.store(&entry_path, &entry) {
+            warn!(path = ?path, "Could not store signature: {}", e);
+        }
+        Ok(entry.signature)
+    }
+
+    fn entry_path(&self, path: &Path) -> PathBuf {
+        let id = xxh3_64(path.as_os_str().as_encoded_bytes());
+        self.dir.join(format!("{id:016x}.json"))
+    }
+
+    fn load(&self, entry_path: &Path) -> Option<CacheEntry> {
+        let contents = fs::read(entry_path).ok()?;
+        match serde_json::from_slice(&contents) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!(entry = ?entry_path, "Ignoring unreadable signature cache entry: {}", e);
+                None
+            }
+        }
+    }
+
+    fn store(&self, entry_path: &Path, entry: &CacheEntry) -> Result<()> {
+        write_atomic(entry_path, |out| Ok(serde_json::to_writer(out, entry)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_reused_until_file_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let cache = SignatureCache::new(dir.join("cache"));
+        let file = dir.join("main.js");
+        fs::write(&file, vec![7u8; 10_000]).unwrap();
+
+        let first = cache.signature_for(&file).unwrap();
+        let entry_path = cache.entry_path(&fs::canonicalize(&file).unwrap());
+        assert!(entry_path.exists());
+
+        // a tampered entry with matching metadata proves the cache is consulted
+        let mut tampered = Signature::new();
+        tampered.build(&[9u8; 10_000]);
+        let mut entry = cache.load(&entry_path).unwrap();
+        entry.signature = tampered.clone();
+        cache.store(&entry_path, &entry).unwrap();
+        assert_eq!(cache.signature_for(&file).unwrap(), tampered);
+
+        fs::write(&file, vec![7u8; 12_000]).unwrap();
+        let rebuilt = cache.signature_for(&file).unwrap();
+        assert_ne!(rebuilt, tampered);
+        assert_ne!(rebuilt, first);
+
+        let cdc = cache
+            .with_strategy(ChunkingStrategy::ContentDefined)
+            .signature_for(&file)
+            .unwrap();
+        assert_eq!(cdc.strategy(), ChunkingStrategy::ContentDefined);
+    }
+}
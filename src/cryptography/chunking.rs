@@ -0,0 +1,174 @@
+//! How a file is cut into the blocks that make up its [`Signature`].
+//!
+//! [`ChunkingStrategy::Fixed`] cuts at every multiple of the block size, which
+//! is cheap but means an insertion shifts every following block.
+//! [`ChunkingStrategy::ContentDefined`] uses FastCDC: a gear hash rolls over
+//! the data and a cut is made wherever the hash matches a mask, so boundaries
+//! depend only on nearby content and resynchronise right after an edit. Both
+//! sides of a sync derive identical boundaries from identical bytes, which
+//! lets chunks be compared one for one and deduplicated across files.
+//!
+//! [`Signature`]: super::signatures::Signature
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ChunkingStrategy {
+    #[default]
+    Fixed,
+    ContentDefined,
+}
+
+/// Random values that the gear hash mixes in for every byte. Generated with
+/// splitmix64 so the table is reproducible without being spelled out.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Average size of content-defined chunks. It is the same for every file,
+/// whatever block size fixed chunking picks for it, so identical content is
+/// cut into identical chunks everywhere.
+pub const CDC_AVG_CHUNK: usize = 4096;
+
+/// Size limits for content-defined chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CdcParams {
+    pub min: usize,
+    pub avg: usize,
+    pub max: usize,
+}
+
+impl Default for CdcParams {
+    fn default() -> Self {
+        Self::around(CDC_AVG_CHUNK)
+    }
+}
+
+impl CdcParams {
+    /// Parameters centred on `avg`, with chunks between a quarter and four
+    /// times that size.
+    pub fn around(avg: usize) -> Self {
+        let avg = avg.max(64).next_power_of_two();
+        Self {
+            min: avg / 4,
+            avg,
+            max: avg * 4,
+        }
+    }
+
+    /// Returns the length of the first chunk of `data`. Only the first `max`
+    /// bytes are looked at, so a chunk can be cut from a partially read
+    /// stream as soon as that many bytes (or the end of input) are available.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        let mut end = data.len();
+        if end <= self.min {
+            return end;
+        }
+        end = end.min(self.max);
+        let normal = self.avg.min(end);
+
+        // normalized chunking: a stricter mask before the average size and a
+        // looser one after it pulls chunk sizes towards the average
+        let bits = self.avg.trailing_zeros();
+        let mask_strict = high_bits_mask(bits + 2);
+        let mask_loose = high_bits_mask(bits.saturating_sub(2));
+
+        let mut hash = 0u64;
+        let mut i = self.min;
+        while i < normal {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & mask_strict == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < end {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & mask_loose == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        end
+    }
+
+    /// Splits `data` into consecutive chunks.
+    pub fn chunks<'a>(&self, data: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+        let params = *self;
+        let mut rest = data;
+        std::iter::from_fn(move || {
+            if rest.is_empty() {
+                return None;
+            }
+            let (chunk, tail) = rest.split_at(params.cut(rest));
+            rest = tail;
+            Some(chunk)
+        })
+    }
+}
+
+/// Mask selecting the `bits` most significant bits of the gear hash, which
+/// depend on the last 64 bytes rolled in rather than only the last few.
+fn high_bits_mask(bits: u32) -> u64 {
+    match bits {
+        0 => 0,
+        b if b >= 64 => u64::MAX,
+        b => u64::MAX << (64 - b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunks_respect_bounds_and_cover_input() {
+        let params = CdcParams::around(4096);
+        let data = pseudo_random(200_000, 1);
+        let chunks: Vec<&[u8]> = params.chunks(&data).collect();
+        assert_eq!(chunks.concat(), data);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= params.min && chunk.len() <= params.max);
+        }
+    }
+
+    #[test]
+    fn test_boundaries_resynchronise_after_insertion() {
+        let params = CdcParams::around(4096);
+        let data = pseudo_random(200_000, 2);
+        let mut edited = b"// a new banner comment\n".to_vec();
+        edited.extend_from_slice(&data);
+
+        let original: Vec<&[u8]> = params.chunks(&data).collect();
+        let shifted: Vec<&[u8]> = params.chunks(&edited).collect();
+        let shared = shifted.iter().filter(|c| original.contains(c)).count();
+        assert!(
+            shared + 2 >= original.len(),
+            "{shared} of {}",
+            original.len()
+        );
+    }
+}
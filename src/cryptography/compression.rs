@@ -0,0 +1,101 @@
+//! Optional compression of the literal data in encoded deltas.
+//!
+//! Codecs are compiled in through the `zstd` and `lz4` cargo features. The
+//! codec is recorded in the delta header, so a build without a codec rejects
+//! deltas that use it instead of misreading them.
+
+use std::io;
+
+use super::wire::WireError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+const ID_NONE: u8 = 0;
+#[cfg(feature = "zstd")]
+const ID_ZSTD: u8 = 1;
+#[cfg(feature = "lz4")]
+const ID_LZ4: u8 = 2;
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// Most literal data a delta may declare. Plugin files are far smaller; the
+/// limit stops a corrupt or hostile header from making us allocate whatever
+/// it asks for.
+pub const MAX_LITERALS_LEN: usize = 256 * 1024 * 1024;
+
+impl Compression {
+    /// Identifier stored in the delta header.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => ID_NONE,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => ID_ZSTD,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => ID_LZ4,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Result<Self, WireError> {
+        match id {
+            ID_NONE => Ok(Compression::None),
+            #[cfg(feature = "zstd")]
+            ID_ZSTD => Ok(Compression::Zstd),
+            #[cfg(feature = "lz4")]
+            ID_LZ4 => Ok(Compression::Lz4),
+            id => Err(WireError::UnsupportedCompression(id)),
+        }
+    }
+
+    pub(crate) fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::compress(data)),
+        }
+    }
+
+    /// Decompresses `data`, which must expand to exactly `raw_len` bytes.
+    /// `raw_len` comes from the input, so it is checked against
+    /// [`MAX_LITERALS_LEN`] before anything is allocated.
+    pub(crate) fn decompress(self, data: &[u8], raw_len: usize) -> Result<Vec<u8>, WireError> {
+        if raw_len > MAX_LITERALS_LEN {
+            return Err(WireError::CorruptLiterals(format!(
+                "{raw_len} bytes of literal data is over the limit of {MAX_LITERALS_LEN}"
+            )));
+        }
+        let raw = match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::bulk::decompress(data, raw_len).map_err(|e| e.to_string()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let mut raw = vec![0; raw_len];
+                lz4_flex::decompress_into(data, &mut raw)
+                    .map(|n| {
+                        raw.truncate(n);
+                        raw
+                    })
+                    .map_err(|e| e.to_string())
+            }
+        }
+        .map_err(WireError::CorruptLiterals)?;
+        if raw.len() != raw_len {
+            return Err(WireError::CorruptLiterals(format!(
+                "expected {raw_len} bytes of literal data, got {}",
+                raw.len()
+            )));
+        }
+        Ok(raw)
+    }
+}
@@ -1,55 +1,383 @@
+//! Rsync style deltas: a list of operations that rebuild a new file from an
+//! old one by copying blocks of the old file and inserting literal bytes.
+//!
+//! # Wire format
+//!
+//! [`Delta::encode`] produces the following layout. All integers written as
+//! `varint` are unsigned LEB128 (see [`super::wire`]).
+//!
+//! ```text
+//! magic      4 bytes   b"OSDL"
+//! version    u8        FORMAT_VERSION
+//! flags      u8        bits 0-1: literal compression (0 none, 1 zstd, 2 lz4)
+//!                      other bits reserved, must be zero
+//! target_len varint    length of the file the delta produces
+//! digest     32 bytes  BLAKE3 hash of the file the delta produces
+//! hash       u8        strong hash of the base signature (0 xxh3, 1 blake3)
+//! base_sig   32 bytes  fingerprint of the base signature
+//! op_count   varint
+//! op_count times:
+//!   tag      u8        0x00 = Copy, 0x01 = Insert
+//!   Copy:    offset varint, len varint   (range of the base file)
+//!   Insert:  len varint, len bytes       (literal data, only when uncompressed)
+//! when compressed:
+//!   raw_len  varint    total length of all Insert data
+//!   packed   varint, bytes   compressed concatenation of all Insert data
+//! ```
+//!
+//! Decoding rejects unknown versions, flags and tags as well as truncated or
+//! trailing input with a [`WireError`]. The length and digest are checked
+//! when the delta is applied, see [`Delta::apply_to`], and the base signature
+//! can be checked with [`Delta::check_signature`].
+
 use std::{
-    fs::File,
-    io::{Seek, SeekFrom, Write},
+    fmt,
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
     path::PathBuf,
 };
 
 use color_eyre::eyre::Result;
 use fbuzhash::BuzHash;
-use xxhash_rust::xxh3::xxh3_64;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::chunking::ChunkingStrategy;
+use super::compression::Compression;
+use super::encryption::EncryptionKey;
+use super::hashing::StrongHashKind;
+use super::identity::{AuthError, Identity, TrustedPeers};
+use super::signatures::{Signature, weak_hash};
+use super::stream::ReadWindow;
+use super::wire::{PayloadKind, WireError, WireReader, write_varint};
+use crate::atomic::write_atomic;
+
+/// Magic bytes at the start of every encoded delta.
+pub const MAGIC: [u8; 4] = *b"OSDL";
+/// Version of the wire format written by [`Delta::encode`].
+pub const FORMAT_VERSION: u8 = 3;
 
-use super::signatures::BLOCK_SIZE;
+const TAG_COPY: u8 = 0x00;
+const TAG_INSERT: u8 = 0x01;
 
-#[derive(Debug)]
+const FLAG_COMPRESSION_MASK: u8 = 0b11;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum DeltaOperations {
     Copy { offset: usize, len: usize },
     Insert { data: Vec<u8> },
 }
 
-#[derive(Debug)]
+impl DeltaOperations {
+    /// Number of bytes the operation writes, ignoring truncation at the end
+    /// of the base.
+    fn len(&self) -> usize {
+        match self {
+            DeltaOperations::Copy { len, .. } => *len,
+            DeltaOperations::Insert { data } => data.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Delta {
     operations: Vec<DeltaOperations>,
+    target_len: u64,
+    target_digest: [u8; 32],
+    base_hash: StrongHashKind,
+    base_fingerprint: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeltaError {
+    /// Applying the delta did not reproduce the file it was generated from,
+    /// usually because the base changed in between.
+    ChecksumMismatch { expected_len: u64, actual_len: u64 },
+    /// The delta was generated against a signature with a different strong
+    /// hash than the one it is being checked against.
+    HashMismatch {
+        delta: StrongHashKind,
+        signature: StrongHashKind,
+    },
+    /// The delta was generated against a different signature, so it does not
+    /// describe changes to this base.
+    SignatureMismatch,
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeltaError::ChecksumMismatch {
+                expected_len,
+                actual_len,
+            } => write!(
+                f,
+                "delta output does not match its checksum (expected {expected_len} bytes, got {actual_len})"
+            ),
+            DeltaError::HashMismatch { delta, signature } => write!(
+                f,
+                "delta was generated with {delta:?} block hashes but the signature uses {signature:?}"
+            ),
+            DeltaError::SignatureMismatch => {
+                write!(f, "delta was generated against a different signature")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+/// An encoded delta along with how well its literal data compressed.
+#[derive(Debug, Clone)]
+pub struct EncodedDelta {
+    pub bytes: Vec<u8>,
+    pub compression: Compression,
+    /// Length of all Insert data before compression.
+    pub literal_len: usize,
+    /// Length of the Insert data as stored in `bytes`.
+    pub stored_literal_len: usize,
+}
+
+impl EncodedDelta {
+    /// Stored size of the literal data relative to its raw size; below 1.0
+    /// means compression paid off.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.literal_len == 0 {
+            return 1.0;
+        }
+        self.stored_literal_len as f64 / self.literal_len as f64
+    }
+}
+
+/// Summary of what a delta does, for judging whether it saves anything over
+/// sending the whole file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeltaStats {
+    pub copy_ops: usize,
+    pub insert_ops: usize,
+    pub bytes_copied: u64,
+    pub bytes_inserted: u64,
+}
+
+impl DeltaStats {
+    /// Fraction of the target file that has to be sent as literal data: 0.0
+    /// when everything is copied from the base, 1.0 when the delta is no
+    /// better than a full transfer.
+    pub fn ratio(&self) -> f64 {
+        let total = self.bytes_copied + self.bytes_inserted;
+        if total == 0 {
+            return 1.0;
+        }
+        self.bytes_inserted as f64 / total as f64
+    }
+}
+
+impl fmt::Display for DeltaStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} copies ({} bytes), {} inserts ({} bytes), {:.1}% of a full transfer",
+            self.copy_ops,
+            self.bytes_copied,
+            self.insert_ops,
+            self.bytes_inserted,
+            self.ratio() * 100.0
+        )
+    }
+}
+
+impl Default for Delta {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Delta {
     pub fn new() -> Self {
+        let empty = Signature::new();
         Self {
             operations: Vec::new(),
+            target_len: 0,
+            target_digest: *blake3::hash(&[]).as_bytes(),
+            base_hash: empty.hash_kind(),
+            base_fingerprint: empty.fingerprint(),
         }
     }
-    pub fn generate_delta(&self, base: &[u8], new_: &[u8]) -> Self {
-        let mut sigs = super::signatures::Signature::new();
-        sigs.build(base).get_entries();
 
-        let mut delta: Vec<DeltaOperations> = Vec::new();
-        let mut insert_buf: Vec<u8> = Vec::new();
+    fn with_operations(
+        operations: Vec<DeltaOperations>,
+        sigs: &Signature,
+        input: &ReadWindow<impl Read>,
+    ) -> Self {
+        let (target_len, digest) = input.digest();
+        let mut delta = Self {
+            operations,
+            target_len,
+            target_digest: digest.expect("delta input is always hashed"),
+            base_hash: sigs.hash_kind(),
+            base_fingerprint: sigs.fingerprint(),
+        };
+        delta.optimize();
+        delta
+    }
 
-        // If new file shorter than block, everything is literal
-        if new_.len() < BLOCK_SIZE {
-            if !new_.is_empty() {
-                delta.push(DeltaOperations::Insert {
-                    data: new_.to_vec(),
+    /// Merges copies of contiguous base ranges and runs of inserts into single
+    /// operations, and drops empty ones. The output of the delta is unchanged.
+    /// Generated deltas are already optimized; this is for deltas built or
+    /// decoded from elsewhere.
+    pub fn optimize(&mut self) {
+        let mut merged: Vec<DeltaOperations> = Vec::with_capacity(self.operations.len());
+        for op in self.operations.drain(..) {
+            match (merged.last_mut(), op) {
+                (_, DeltaOperations::Copy { len: 0, .. }) => {}
+                (_, DeltaOperations::Insert { data }) if data.is_empty() => {}
+                (
+                    Some(DeltaOperations::Copy { offset, len }),
+                    DeltaOperations::Copy {
+                        offset: next_offset,
+                        len: next_len,
+                    },
+                ) if offset.checked_add(*len) == Some(next_offset)
+                    && len.checked_add(next_len).is_some() =>
+                {
+                    *len += next_len
+                }
+                (
+                    Some(DeltaOperations::Insert { data }),
+                    DeltaOperations::Insert { data: next },
+                ) => data.extend_from_slice(&next),
+                (_, op) => merged.push(op),
+            }
+        }
+        self.operations = merged;
+    }
+
+    /// Length of the file this delta produces.
+    pub fn target_len(&self) -> u64 {
+        self.target_len
+    }
+
+    /// BLAKE3 hash of the file this delta produces.
+    pub fn target_digest(&self) -> [u8; 32] {
+        self.target_digest
+    }
+
+    /// Strong hash of the signature this delta was generated against.
+    pub fn base_hash(&self) -> StrongHashKind {
+        self.base_hash
+    }
+
+    /// Checks that this delta was generated against `sigs`. A receiver that
+    /// signed its base file should call this before applying a delta that
+    /// came from elsewhere.
+    pub fn check_signature(&self, sigs: &Signature) -> Result<(), DeltaError> {
+        if self.base_hash != sigs.hash_kind() {
+            return Err(DeltaError::HashMismatch {
+                delta: self.base_hash,
+                signature: sigs.hash_kind(),
+            });
+        }
+        if self.base_fingerprint != sigs.fingerprint() {
+            return Err(DeltaError::SignatureMismatch);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> DeltaStats {
+        let mut stats = DeltaStats::default();
+        for op in &self.operations {
+            match op {
+                DeltaOperations::Copy { len, .. } => {
+                    stats.copy_ops += 1;
+                    stats.bytes_copied += *len as u64;
+                }
+                DeltaOperations::Insert { data } => {
+                    stats.insert_ops += 1;
+                    stats.bytes_inserted += data.len() as u64;
+                }
+            }
+        }
+        stats
+    }
+
+    /// The single delta equivalent to applying `a` and then applying `b` to
+    /// the result. Copies in `b` are resolved to the operations of `a` that
+    /// produced those bytes, so the composed delta applies to the base of `a`
+    /// and produces the target of `b`. If `b` was not generated against the
+    /// output of `a`, applying the result fails its checksum.
+    pub fn compose(a: &Delta, b: &Delta) -> Delta {
+        // start of each of a's operations within a's output
+        let mut starts = Vec::with_capacity(a.operations.len());
+        let mut a_len = 0;
+        for op in &a.operations {
+            starts.push(a_len);
+            a_len += op.len();
+        }
+
+        let mut operations = Vec::new();
+        for op in &b.operations {
+            let (mut pos, end) = match op {
+                DeltaOperations::Insert { data } => {
+                    operations.push(DeltaOperations::Insert { data: data.clone() });
+                    continue;
+                }
+                DeltaOperations::Copy { offset, len } => {
+                    (*offset, offset.saturating_add(*len).min(a_len))
+                }
+            };
+            let mut i = starts
+                .partition_point(|&start| start <= pos)
+                .saturating_sub(1);
+            while pos < end {
+                let skip = pos - starts[i];
+                let take = (a.operations[i].len() - skip).min(end - pos);
+                operations.push(match &a.operations[i] {
+                    DeltaOperations::Copy { offset, .. } => DeltaOperations::Copy {
+                        offset: offset + skip,
+                        len: take,
+                    },
+                    DeltaOperations::Insert { data } => DeltaOperations::Insert {
+                        data: data[skip..skip + take].to_vec(),
+                    },
                 });
+                pos += take;
+                i += 1;
             }
-            return Delta { operations: delta };
         }
 
-        let mut pos = 0usize;
+        let mut composed = Delta {
+            operations,
+            target_len: b.target_len,
+            target_digest: b.target_digest,
+            base_hash: a.base_hash,
+            base_fingerprint: a.base_fingerprint,
+        };
+        composed.optimize();
+        composed
+    }
 
-        // seed the rolling buzhash with first BLOCK_SIZE bytes
-        let mut bh = BuzHash::new(BLOCK_SIZE as u32);
-        bh.write_all(&new_[0..BLOCK_SIZE]).unwrap();
-        let mut weak = bh.sum32();
+    pub fn generate_delta(&self, base: &[u8], new_: &[u8]) -> Self {
+        let mut sigs = Signature::new();
+        sigs.build(base);
+        Self::from_signature(&sigs, new_)
+    }
+
+    /// Generates the delta from the file described by `sigs` to `new_`, for
+    /// when the base file's signature is already known.
+    pub fn from_signature(sigs: &Signature, new_: &[u8]) -> Self {
+        Self::from_reader(sigs, new_).expect("reading from a slice cannot fail")
+    }
+
+    /// Streaming form of [`Delta::from_signature`]. Only a window of about one
+    /// block of `new_` is held at a time, besides the literal data that ends
+    /// up in the delta itself.
+    pub fn from_reader(sigs: &Signature, new_: impl Read) -> Result<Self> {
+        let mut input = ReadWindow::hashing(new_);
+        if sigs.strategy() == ChunkingStrategy::ContentDefined {
+            return Self::from_chunks(sigs, &mut input);
+        }
+        let mut delta: Vec<DeltaOperations> = Vec::new();
+        let mut insert_buf: Vec<u8> = Vec::new();
+        let block_size = sigs.block_size();
 
         // helper to flush inserts
         let flush_inserts = |delta: &mut Vec<DeltaOperations>, buf: &mut Vec<u8>| {
@@ -60,94 +388,520 @@ impl Delta {
             }
         };
 
-        while pos + BLOCK_SIZE <= new_.len() {
-            let window = &new_[pos..pos + BLOCK_SIZE];
-            let mut matched = false;
+        // an empty base has no blocks, so everything is literal
+        if block_size == 0 {
+            loop {
+                let available = input.fill(1)?;
+                if available.is_empty() {
+                    break;
+                }
+                insert_buf.extend_from_slice(available);
+                let len = available.len();
+                input.consume(len);
+            }
+            flush_inserts(&mut delta, &mut insert_buf);
+            return Ok(Self::with_operations(delta, sigs, &input));
+        }
 
-            if let Some(cands) = sigs.get_entries().get(&weak) {
-                let strong = xxh3_64(window);
+        // If new file shorter than block, everything is literal
+        let window = input.fill(block_size)?;
+        if window.len() < block_size {
+            insert_buf.extend_from_slice(window);
+            let len = window.len();
+            input.consume(len);
+            flush_inserts(&mut delta, &mut insert_buf);
+            return Ok(Self::with_operations(delta, sigs, &input));
+        }
 
-                // find candidate with same strong hash and same length (full block)
-                if let Some(entry) = cands
-                    .iter()
-                    .find(|e| e.len == BLOCK_SIZE && e.strong == strong)
-                {
-                    // confirmed match
-                    flush_inserts(&mut delta, &mut insert_buf);
-                    delta.push(DeltaOperations::Copy {
-                        offset: entry.offset,
-                        len: entry.len,
-                    });
-
-                    pos += BLOCK_SIZE;
-
-                    // If there are enough bytes left, reseed the buzhash for the next aligned window
-                    if pos + BLOCK_SIZE <= new_.len() {
-                        bh = BuzHash::new(BLOCK_SIZE as u32);
-                        bh.write_all(&new_[pos..pos + BLOCK_SIZE]).unwrap();
-                        weak = bh.sum32();
-                    } else {
-                        // append leftover tail (if any) as insert and finish
-                        if pos < new_.len() {
-                            insert_buf.extend_from_slice(&new_[pos..]);
-                        }
-                        flush_inserts(&mut delta, &mut insert_buf);
-                        break;
-                    }
+        // seed the rolling buzhash with first block_size bytes
+        let mut bh = BuzHash::new(block_size as u32);
+        bh.write_all(&window[..block_size]).unwrap();
+        let mut weak = bh.sum32();
+        let hasher = sigs.hash_kind().hasher();
 
-                    matched = true;
-                }
+        // invariant: at least block_size bytes are buffered and `bh` covers them
+        loop {
+            let window = &input.fill(block_size)?[..block_size];
+            let mut matched = None;
+
+            if sigs.get_entries().contains_key(&weak) {
+                let strong = hasher.digest(window);
+
+                // find candidate with same strong hash and same length (full block)
+                matched = sigs.find(weak, strong, block_size);
             }
 
-            if !matched {
+            if let Some(entry) = matched {
+                // confirmed match
+                flush_inserts(&mut delta, &mut insert_buf);
+                delta.push(DeltaOperations::Copy {
+                    offset: entry.offset,
+                    len: entry.len,
+                });
+                input.consume(block_size);
+
+                // If there are enough bytes left, reseed the buzhash for the next aligned window
+                let window = input.fill(block_size)?;
+                if window.len() >= block_size {
+                    bh = BuzHash::new(block_size as u32);
+                    bh.write_all(&window[..block_size]).unwrap();
+                    weak = bh.sum32();
+                } else {
+                    // append leftover tail (if any) as insert and finish
+                    insert_buf.extend_from_slice(window);
+                    let len = window.len();
+                    input.consume(len);
+                    break;
+                }
+            } else {
                 // no match: emit first byte of current window into insert buffer and slide by 1
-                insert_buf.push(new_[pos]);
-                pos += 1;
+                insert_buf.push(window[0]);
+                input.consume(1);
 
                 // if not enough to form a full window after sliding, everything left is literal
-                if pos + BLOCK_SIZE > new_.len() {
-                    insert_buf.extend_from_slice(&new_[pos..]);
-                    flush_inserts(&mut delta, &mut insert_buf);
+                let window = input.fill(block_size)?;
+                if window.len() < block_size {
+                    insert_buf.extend_from_slice(window);
+                    let len = window.len();
+                    input.consume(len);
                     break;
-                } else {
-                    // slide the rolling hash by feeding next byte (BuzHash handles the buffer internals)
-                    let next_in = new_[pos + BLOCK_SIZE - 1];
-                    let _ = bh.hash_byte(next_in); // returns u32 but we just update state
-                    weak = bh.sum32();
                 }
+                // slide the rolling hash by feeding next byte (BuzHash handles the buffer internals)
+                let next_in = window[block_size - 1];
+                let _ = bh.hash_byte(next_in); // returns u32 but we just update state
+                weak = bh.sum32();
             }
         }
-        Delta { operations: delta }
+        flush_inserts(&mut delta, &mut insert_buf);
+        Ok(Self::with_operations(delta, sigs, &input))
+    }
+
+    /// Content-defined counterpart of the rolling search: the input is cut
+    /// with the same parameters as the base, so every chunk either exists in
+    /// the signature as a whole or becomes literal data.
+    fn from_chunks(sigs: &Signature, input: &mut ReadWindow<impl Read>) -> Result<Self> {
+        let params = sigs.cdc_params();
+        let hasher = sigs.hash_kind().hasher();
+        let mut operations = Vec::new();
+        loop {
+            let available = input.fill(params.max)?;
+            if available.is_empty() {
+                break;
+            }
+            let chunk = &available[..params.cut(available)];
+            let op = match sigs.find(weak_hash(chunk), hasher.digest(chunk), chunk.len()) {
+                Some(entry) => DeltaOperations::Copy {
+                    offset: entry.offset,
+                    len: entry.len,
+                },
+                None => DeltaOperations::Insert {
+                    data: chunk.to_vec(),
+                },
+            };
+            let len = chunk.len();
+            operations.push(op);
+            input.consume(len);
+        }
+        Ok(Self::with_operations(operations, sigs, input))
     }
 
     pub fn apply(&self, base: &[u8], out_path: PathBuf) -> Result<()> {
-        let mut out = File::create("temp_out").unwrap();
-        let delta = &self.operations;
-        for op in delta {
+        self.apply_from(Cursor::new(base), out_path)
+    }
+
+    /// Applies the delta like [`Delta::apply_from`] and returns its reverse,
+    /// which turns the new file back into the old one. Keeping reverse deltas
+    /// is a cheap way to remember earlier versions of a file.
+    pub fn apply_with_reverse(
+        &self,
+        mut base: impl Read + Seek,
+        out_path: PathBuf,
+    ) -> Result<Self> {
+        let reverse = self.reverse(&mut base)?;
+        self.apply_from(base, out_path)?;
+        Ok(reverse)
+    }
+
+    /// Builds the delta that turns the output of this delta back into
+    /// `base`. Every range of `base` that this delta copies becomes a copy
+    /// from the new file; everything else is inserted from `base`, which is
+    /// read once from start to end.
+    ///
+    /// The reverse delta was not generated against a signature, so it should
+    /// only be applied to the exact file this delta produces. The target
+    /// digest still catches any other base.
+    pub fn reverse(&self, base: &mut (impl Read + Seek)) -> Result<Self> {
+        let base_len = base.seek(SeekFrom::End(0))? as usize;
+        // (offset in base, offset in new file, len) of every copy
+        let mut copies = Vec::new();
+        let mut new_pos = 0;
+        for op in &self.operations {
+            if let DeltaOperations::Copy { offset, len } = *op {
+                let len = len.min(base_len.saturating_sub(offset));
+                if len > 0 {
+                    copies.push((offset, new_pos, len));
+                }
+            }
+            new_pos += op.len();
+        }
+        copies.sort_unstable();
+
+        base.seek(SeekFrom::Start(0))?;
+        let mut hasher = blake3::Hasher::new();
+        let mut operations = Vec::new();
+        let mut pos = 0;
+        let insert = |base: &mut dyn Read, hasher: &mut blake3::Hasher, len| {
+            let mut data = vec![0; len];
+            base.read_exact(&mut data)?;
+            hasher.update(&data);
+            Ok::<_, io::Error>(DeltaOperations::Insert { data })
+        };
+        for (offset, new_offset, len) in copies {
+            let end = offset + len;
+            if end <= pos {
+                continue;
+            }
+            if offset > pos {
+                operations.push(insert(base, &mut hasher, offset - pos)?);
+                pos = offset;
+            }
+            io::copy(&mut base.by_ref().take((end - pos) as u64), &mut hasher)?;
+            operations.push(DeltaOperations::Copy {
+                offset: new_offset + (pos - offset),
+                len: end - pos,
+            });
+            pos = end;
+        }
+        if pos < base_len {
+            operations.push(insert(base, &mut hasher, base_len - pos)?);
+        }
+
+        let unsigned = Signature::new();
+        let mut reverse = Self {
+            operations,
+            target_len: base_len as u64,
+            target_digest: *hasher.finalize().as_bytes(),
+            base_hash: unsigned.hash_kind(),
+            base_fingerprint: unsigned.fingerprint(),
+        };
+        reverse.optimize();
+        Ok(reverse)
+    }
+
+    /// Like [`Delta::apply`], but reads the base through `base` instead of
+    /// needing all of it in memory.
+    /// The result is written next to `out_path` and renamed over it only once
+    /// it is complete (see [`write_atomic`]).
+    pub fn apply_from(&self, mut base: impl Read + Seek, out_path: PathBuf) -> Result<()> {
+        write_atomic(&out_path, |mut out| self.apply_to(&mut base, &mut out))
+    }
+
+    /// Writes the new file into `out`, copying ranges of `base` as the
+    /// operations ask for them. Copies past the end of `base` are truncated.
+    ///
+    /// Fails with [`DeltaError::ChecksumMismatch`] if what was written does
+    /// not match the length and digest recorded when the delta was generated.
+    pub fn apply_to(&self, base: &mut (impl Read + Seek), out: &mut impl Write) -> Result<()> {
+        let mut out = HashingWriter {
+            inner: out,
+            hasher: blake3::Hasher::new(),
+            len: 0,
+        };
+        // position of `base`, so runs of sequential copies do not seek
+        let mut pos = None;
+        for op in &self.operations {
             match op {
                 DeltaOperations::Copy { offset, len } => {
-                    let start = *offset;
-                    let end = start.saturating_add(*len).min(base.len());
-                    if start < base.len() && start < end {
-                        out.write_all(&base[start..end])?;
+                    let offset = *offset as u64;
+                    if pos != Some(offset) {
+                        base.seek(SeekFrom::Start(offset))?;
                     }
+                    let copied = io::copy(&mut base.by_ref().take(*len as u64), &mut out)?;
+                    pos = Some(offset + copied);
                 }
                 DeltaOperations::Insert { data } => {
                     out.write_all(data)?;
                 }
             }
         }
-        out.flush()?;
-        std::fs::rename("temp_out", out_path)?;
-        let _ = std::fs::remove_file("temp_out");
+        if out.len != self.target_len || *out.hasher.finalize().as_bytes() != self.target_digest {
+            return Err(DeltaError::ChecksumMismatch {
+                expected_len: self.target_len,
+                actual_len: out.len,
+            }
+            .into());
+        }
         Ok(())
     }
+
+    /// Serializes the delta into the versioned wire format described in the
+    /// module documentation, without compression.
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with(Compression::None)
+            .expect("encoding without compression cannot fail")
+            .bytes
+    }
+
+    /// Serializes the delta, compressing all literal data with `compression`.
+    pub fn encode_with(&self, compression: Compression) -> Result<EncodedDelta> {
+        let inline = compression == Compression::None;
+        let mut literals = Vec::new();
+        let mut out = Vec::new();
+        out.extend_from_slice(&MAGIC);
+        out.push(FORMAT_VERSION);
+        out.push(compression.id());
+        write_varint(&mut out, self.target_len);
+        out.extend_from_slice(&self.target_digest);
+        out.push(self.base_hash.id());
+        out.extend_from_slice(&self.base_fingerprint);
+        write_varint(&mut out, self.operations.len() as u64);
+        for op in &self.operations {
+            match op {
+                DeltaOperations::Copy { offset, len } => {
+                    out.push(TAG_COPY);
+                    write_varint(&mut out, *offset as u64);
+                    write_varint(&mut out, *len as u64);
+                }
+                DeltaOperations::Insert { data } => {
+                    out.push(TAG_INSERT);
+                    write_varint(&mut out, data.len() as u64);
+                    if inline {
+                        out.extend_from_slice(data);
+                    } else {
+                        literals.extend_from_slice(data);
+                    }
+                }
+            }
+        }
+
+        let literal_len = self.stats().bytes_inserted as usize;
+        let mut stored_literal_len = literal_len;
+        if !inline {
+            let packed = compression.compress(&literals)?;
+            stored_literal_len = packed.len();
+            write_varint(&mut out, literals.len() as u64);
+            write_varint(&mut out, packed.len() as u64);
+            out.extend_from_slice(&packed);
+            debug!(
+                ?compression,
+                literal_len, stored_literal_len, "Compressed delta literals"
+            );
+        }
+        Ok(EncodedDelta {
+            bytes: out,
+            compression,
+            literal_len,
+            stored_literal_len,
+        })
+    }
+
+    /// Parses a delta produced by [`Delta::encode`] or [`Delta::encode_with`].
+    pub fn decode(bytes: &[u8]) -> Result<Self, WireError> {
+        let mut reader = WireReader::new(bytes);
+        if reader
+            .read_bytes(MAGIC.len())
+            .map_err(|_| WireError::BadMagic)?
+            != MAGIC
+        {
+            return Err(WireError::BadMagic);
+        }
+        let version = reader.read_u8()?;
+        if version != FORMAT_VERSION {
+            return Err(WireError::UnsupportedVersion(version));
+        }
+        let flags = reader.read_u8()?;
+        if flags & !FLAG_COMPRESSION_MASK != 0 {
+            return Err(WireError::UnsupportedFlags(flags));
+        }
+        let compression = Compression::from_id(flags & FLAG_COMPRESSION_MASK)?;
+        let inline = compression == Compression::None;
+        let target_len = reader.read_varint()?;
+        let target_digest = reader.read_array()?;
+        let hash_id = reader.read_u8()?;
+        let base_hash = StrongHashKind::from_id(hash_id).ok_or(WireError::UnknownHash(hash_id))?;
+        let base_fingerprint = reader.read_array()?;
+        let count = reader.read_len()?;
+        // every operation takes at least two bytes, so a count larger than that
+        // can only come from a corrupt header
+        let mut operations = Vec::with_capacity(count.min(reader.remaining() / 2));
+        let mut insert_lens = Vec::new();
+        let mut inserted = 0usize;
+        // bytes the operations produce, checked so later arithmetic on
+        // offsets and lengths cannot overflow
+        let mut output = 0usize;
+        for _ in 0..count {
+            let op = match reader.read_u8()? {
+                TAG_COPY => {
+                    let offset = reader.read_len()?;
+                    let len = reader.read_len()?;
+                    if offset.checked_add(len).is_none() {
+                        return Err(WireError::OperationsOverflow);
+                    }
+                    output = output
+                        .checked_add(len)
+                        .ok_or(WireError::OperationsOverflow)?;
+                    DeltaOperations::Copy { offset, len }
+                }
+                TAG_INSERT => {
+                    let len = reader.read_len()?;
+                    output = output
+                        .checked_add(len)
+                        .ok_or(WireError::OperationsOverflow)?;
+                    let data = if inline {
+                        reader.read_bytes(len)?.to_vec()
+                    } else {
+                        // filled in from the literal section below
+                        inserted = inserted.checked_add(len).ok_or_else(|| {
+                            WireError::CorruptLiterals("inserts are too long".to_owned())
+                        })?;
+                        insert_lens.push(len);
+                        Vec::new()
+                    };
+                    DeltaOperations::Insert { data }
+                }
+                tag => return Err(WireError::UnknownOperation(tag)),
+            };
+            operations.push(op);
+        }
+        if !inline {
+            let raw_len = reader.read_len()?;
+            if raw_len != inserted {
+                return Err(WireError::CorruptLiterals(format!(
+                    "{raw_len} bytes of literal data for {inserted} bytes of inserts"
+                )));
+            }
+            let packed_len = reader.read_len()?;
+            let literals = compression.decompress(reader.read_bytes(packed_len)?, raw_len)?;
+            let mut rest = &literals[..];
+            let mut lens = insert_lens.into_iter();
+            for op in &mut operations {
+                if let DeltaOperations::Insert { data } = op {
+                    let len = lens.next().expect("one length per insert");
+                    if rest.len() < len {
+                        return Err(WireError::CorruptLiterals(
+                            "inserts are longer than the literal data".to_owned(),
+                        ));
+                    }
+                    let (head, tail) = rest.split_at(len);
+                    *data = head.to_vec();
+                    rest = tail;
+                }
+            }
+            if !rest.is_empty() {
+                return Err(WireError::CorruptLiterals(
+                    "literal data is longer than the inserts".to_owned(),
+                ));
+            }
+        }
+        reader.finish()?;
+        Ok(Delta {
+            operations,
+            target_len,
+            target_digest,
+            base_hash,
+            base_fingerprint,
+        })
+    }
+
+    /// Encodes the delta and wraps it in an envelope signed by `identity`,
+    /// for sending to another machine.
+    pub fn encode_signed(&self, identity: &Identity, compression: Compression) -> Result<Vec<u8>> {
+        let encoded = self.encode_with(compression)?;
+        Ok(identity.sign(PayloadKind::Delta, &encoded.bytes))
+    }
+
+    /// Parses a delta from an envelope made by [`Delta::encode_signed`]. The
+    /// envelope is rejected before decoding unless a trusted peer signed it.
+    pub fn decode_signed(envelope: &[u8], peers: &TrustedPeers) -> Result<Self, AuthError> {
+        let payload = peers.verify(envelope, PayloadKind::Delta)?;
+        Ok(Self::decode(payload)?)
+    }
+
+    /// Signs the delta like [`Delta::encode_signed`] and encrypts the signed
+    /// envelope, for routing through storage we do not trust.
+    pub fn encode_sealed(
+        &self,
+        identity: &Identity,
+        key: &EncryptionKey,
+        compression: Compression,
+    ) -> Result<Vec<u8>> {
+        let signed = self.encode_signed(identity, compression)?;
+        Ok(key.seal(PayloadKind::Delta, &signed))
+    }
+
+    /// Decrypts and verifies an envelope made by [`Delta::encode_sealed`].
+    pub fn decode_sealed(
+        envelope: &[u8],
+        key: &EncryptionKey,
+        peers: &TrustedPeers,
+    ) -> Result<Self, AuthError> {
+        let signed = key.open(envelope, PayloadKind::Delta)?;
+        Self::decode_signed(&signed, peers)
+    }
+}
+
+/// Inserts longer than this are shortened in the [`Delta`] dump.
+const DUMP_PREVIEW_LEN: usize = 32;
+
+/// Human readable dump: a summary line followed by one line per operation.
+impl fmt::Display for Delta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "delta to {} bytes (blake3 {}): {}",
+            self.target_len,
+            blake3::Hash::from_bytes(self.target_digest).to_hex(),
+            self.stats()
+        )?;
+        for (i, op) in self.operations.iter().enumerate() {
+            match op {
+                DeltaOperations::Copy { offset, len } => {
+                    writeln!(f, "{i:>6}  copy    offset={offset} len={len}")?
+                }
+                DeltaOperations::Insert { data } => {
+                    let preview = &data[..data.len().min(DUMP_PREVIEW_LEN)];
+                    let more = if data.len() > DUMP_PREVIEW_LEN {
+                        "..."
+                    } else {
+                        ""
+                    };
+                    writeln!(
+                        f,
+                        "{i:>6}  insert  len={} \"{}{more}\"",
+                        data.len(),
+                        preview.escape_ascii()
+                    )?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Passes writes through while hashing and counting them.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: blake3::Hasher,
+    len: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::io::Read;
 
     #[test]
@@ -167,4 +921,470 @@ mod tests {
         let new_ = std::fs::read("test/to.txt").unwrap();
         pretty_assertions::assert_eq!(base, new_);
     }
+
+    fn sample_delta() -> Delta {
+        let base: Vec<u8> = (0..3 * 4096).map(|i| (i % 251) as u8).collect();
+        let mut new_ = base.clone();
+        new_.splice(4096..4096, b"inserted".iter().copied());
+        Delta::new().generate_delta(&base, &new_)
+    }
+
+    #[test]
+    fn test_content_defined_delta() {
+        let base: Vec<u8> = (0..64 * 1024u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let mut new_ = b"/* prepended */".to_vec();
+        new_.extend_from_slice(&base);
+
+        let mut sigs = Signature::with_strategy(ChunkingStrategy::ContentDefined);
+        sigs.build(&base);
+        let delta = Delta::from_signature(&sigs, &new_);
+        assert!(
+            delta
+                .operations
+                .iter()
+                .any(|op| matches!(op, DeltaOperations::Copy { .. }))
+        );
+
+        let mut out = Vec::new();
+        for op in &delta.operations {
+            match op {
+                DeltaOperations::Copy { offset, len } => {
+                    out.extend_from_slice(&base[*offset..offset + len])
+                }
+                DeltaOperations::Insert { data } => out.extend_from_slice(data),
+            }
+        }
+        pretty_assertions::assert_eq!(out, new_);
+    }
+
+    #[test]
+    fn test_streaming_matches_in_memory() {
+        let base: Vec<u8> = (0..50_000u32).map(|i| (i * 7 % 253) as u8).collect();
+        let mut new_ = base[..20_000].to_vec();
+        new_.extend_from_slice(b"a change in the middle");
+        new_.extend_from_slice(&base[20_100..]);
+
+        for strategy in [ChunkingStrategy::Fixed, ChunkingStrategy::ContentDefined] {
+            let mut sigs = Signature::with_strategy(strategy);
+            sigs.build(&base);
+            let delta = Delta::from_signature(&sigs, &new_);
+            let streamed =
+                Delta::from_reader(&sigs, io::BufReader::with_capacity(7, &new_[..])).unwrap();
+            assert_eq!(streamed, delta);
+
+            let mut out = Vec::new();
+            delta.apply_to(&mut Cursor::new(&base), &mut out).unwrap();
+            pretty_assertions::assert_eq!(out, new_);
+        }
+
+        let empty = Signature::new();
+        let delta = Delta::from_reader(&empty, &new_[..]).unwrap();
+        let mut out = Vec::new();
+        delta.apply_to(&mut Cursor::new(&[]), &mut out).unwrap();
+        pretty_assertions::assert_eq!(out, new_);
+    }
+
+    #[test]
+    fn test_apply_rejects_stale_base() {
+        let base: Vec<u8> = (0..20_000u32).map(|i| (i % 241) as u8).collect();
+        let mut new_ = base.clone();
+        new_[10_000] ^= 0xff;
+        let delta = Delta::new().generate_delta(&base, &new_);
+
+        let mut stale = base.clone();
+        stale[100] ^= 0xff;
+        let dir = tempfile::tempdir().unwrap();
+        let out_path = dir.path().join("data.json");
+        std::fs::write(&out_path, &stale).unwrap();
+
+        let err = delta.apply(&stale, out_path.clone()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DeltaError>(),
+            Some(&DeltaError::ChecksumMismatch {
+                expected_len: 20_000,
+                actual_len: 20_000
+            })
+        );
+        assert_eq!(std::fs::read(&out_path).unwrap(), stale);
+
+        delta.apply(&base, out_path.clone()).unwrap();
+        assert_eq!(std::fs::read(&out_path).unwrap(), new_);
+    }
+
+    #[test]
+    fn test_optimize_merges_neighbours() {
+        let mut delta = Delta::new();
+        delta.operations = vec![
+            DeltaOperations::Copy { offset: 0, len: 10 },
+            DeltaOperations::Copy { offset: 10, len: 5 },
+            DeltaOperations::Copy { offset: 40, len: 0 },
+            DeltaOperations::Copy { offset: 30, len: 5 },
+            DeltaOperations::Insert {
+                data: b"ab".to_vec(),
+            },
+            DeltaOperations::Insert { data: Vec::new() },
+            DeltaOperations::Insert {
+                data: b"c".to_vec(),
+            },
+            DeltaOperations::Copy { offset: 35, len: 1 },
+        ];
+        delta.optimize();
+        assert_eq!(
+            delta.operations,
+            vec![
+                DeltaOperations::Copy { offset: 0, len: 15 },
+                DeltaOperations::Copy { offset: 30, len: 5 },
+                DeltaOperations::Insert {
+                    data: b"abc".to_vec()
+                },
+                DeltaOperations::Copy { offset: 35, len: 1 },
+            ]
+        );
+
+        // offsets from a decoded delta can be anything
+        let mut delta = Delta::new();
+        let wrapping = vec![
+            DeltaOperations::Copy {
+                offset: usize::MAX,
+                len: 1,
+            },
+            DeltaOperations::Copy { offset: 0, len: 1 },
+        ];
+        delta.operations = wrapping.clone();
+        delta.optimize();
+        assert_eq!(delta.operations, wrapping);
+
+        // an unchanged file collapses to a single copy (320 blocks of 320 bytes)
+        let base: Vec<u8> = (0..102_400u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 11) as u8)
+            .collect();
+        let delta = Delta::new().generate_delta(&base, &base);
+        assert_eq!(
+            delta.operations,
+            vec![DeltaOperations::Copy {
+                offset: 0,
+                len: base.len()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_signature_pairing() {
+        let base: Vec<u8> = (0..30_000u32).map(|i| (i % 233) as u8).collect();
+        let mut new_ = base.clone();
+        new_.truncate(25_000);
+
+        let mut sigs = Signature::new().with_hash(StrongHashKind::Blake3);
+        sigs.build(&base);
+        let delta = Delta::from_signature(&sigs, &new_);
+        assert_eq!(delta.base_hash(), StrongHashKind::Blake3);
+        assert_eq!(delta.check_signature(&sigs), Ok(()));
+        let decoded = Delta::decode(&delta.encode()).unwrap();
+        assert_eq!(decoded.check_signature(&sigs), Ok(()));
+
+        let mut xxh3 = Signature::new();
+        xxh3.build(&base);
+        assert_eq!(
+            delta.check_signature(&xxh3),
+            Err(DeltaError::HashMismatch {
+                delta: StrongHashKind::Blake3,
+                signature: StrongHashKind::Xxh3
+            })
+        );
+
+        let mut other = Signature::new().with_hash(StrongHashKind::Blake3);
+        other.build(&new_);
+        assert_eq!(
+            delta.check_signature(&other),
+            Err(DeltaError::SignatureMismatch)
+        );
+
+        let mut out = Vec::new();
+        delta.apply_to(&mut Cursor::new(&base), &mut out).unwrap();
+        pretty_assertions::assert_eq!(out, new_);
+    }
+
+    #[test]
+    fn test_signed_delta_requires_trusted_peer() {
+        use crate::cryptography::identity::{AuthError, Identity, TrustedPeers};
+
+        let base = b"export default class Plugin {}".repeat(40);
+        let mut new_ = base.clone();
+        new_.extend_from_slice(b"fetch('https://example.invalid')");
+        let mut sigs = Signature::new();
+        sigs.build(&base);
+        let delta = Delta::from_signature(&sigs, &new_);
+
+        let peer = Identity::generate();
+        let mut peers = TrustedPeers::default();
+        let envelope = delta.encode_signed(&peer, Compression::None).unwrap();
+        assert_eq!(
+            Delta::decode_signed(&envelope, &peers).unwrap_err(),
+            AuthError::UntrustedKey(peer.public_key())
+        );
+
+        peers.insert("desktop", peer.public_key());
+        let received = Delta::decode_signed(&envelope, &peers).unwrap();
+        assert_eq!(received.check_signature(&sigs), Ok(()));
+        let mut out = Vec::new();
+        received
+            .apply_to(&mut Cursor::new(&base), &mut out)
+            .unwrap();
+        pretty_assertions::assert_eq!(out, new_);
+
+        let table = sigs.encode_signed(&peer);
+        assert_eq!(Signature::decode_signed(&table, &peers), Ok(sigs));
+        assert!(matches!(
+            Delta::decode_signed(&table, &peers),
+            Err(AuthError::UnexpectedKind { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sealed_delta_roundtrip() {
+        use crate::cryptography::encryption::EncryptionKey;
+        use crate::cryptography::identity::{AuthError, Identity, TrustedPeers};
+
+        let base = br#"{"apiKey":"sk-old","theme":"dark"}"#.repeat(20);
+        let new_ = br#"{"apiKey":"sk-new","theme":"dark"}"#.repeat(20);
+        let mut sigs = Signature::new();
+        sigs.build(&base);
+        let delta = Delta::from_signature(&sigs, &new_);
+
+        let peer = Identity::generate();
+        let mut peers = TrustedPeers::default();
+        peers.insert("desktop", peer.public_key());
+        let key = EncryptionKey::from_passphrase("shared", [7; 16]).unwrap();
+        let envelope = delta.encode_sealed(&peer, &key, Compression::None).unwrap();
+        assert!(!envelope.windows(6).any(|w| w == b"sk-new"));
+
+        let received = Delta::decode_sealed(&envelope, &key, &peers).unwrap();
+        let mut out = Vec::new();
+        received
+            .apply_to(&mut Cursor::new(&base), &mut out)
+            .unwrap();
+        pretty_assertions::assert_eq!(out, new_);
+
+        let other = EncryptionKey::from_passphrase("guess", [7; 16]).unwrap();
+        assert_eq!(
+            Delta::decode_sealed(&envelope, &other, &peers).unwrap_err(),
+            AuthError::DecryptionFailed
+        );
+    }
+
+    #[test]
+    fn test_compose_matches_sequential_application() {
+        let v1: Vec<u8> = (0..50_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut v2 = v1.clone();
+        v2.splice(10_000..10_000, b"inserted in the middle".iter().copied());
+        v2.truncate(45_000);
+        let mut v3 = b"new header\n".to_vec();
+        v3.extend_from_slice(&v2[..20_000]);
+        v3.extend_from_slice(&v2[30_000..]);
+
+        let a = Delta::new().generate_delta(&v1, &v2);
+        let b = Delta::new().generate_delta(&v2, &v3);
+        let composed = Delta::compose(&a, &b);
+        let mut out = Vec::new();
+        composed.apply_to(&mut Cursor::new(&v1), &mut out).unwrap();
+        pretty_assertions::assert_eq!(out, v3);
+
+        let mut v1_sigs = Signature::new();
+        v1_sigs.build(&v1);
+        assert_eq!(composed.check_signature(&v1_sigs), Ok(()));
+
+        // b does not describe changes to v1, so composing it with itself
+        // produces a delta whose checksum fails
+        let bogus = Delta::compose(&b, &b);
+        let mut out = Vec::new();
+        let err = bogus.apply_to(&mut Cursor::new(&v1), &mut out).unwrap_err();
+        assert!(err.downcast_ref::<DeltaError>().is_some());
+    }
+
+    #[test]
+    fn test_reverse_delta_restores_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.json");
+        let old: Vec<u8> = (0..40_000u32).map(|i| (i * 13 % 241) as u8).collect();
+        let mut new_ = old[5_000..].to_vec();
+        new_.extend_from_slice(&old[..1_000]);
+        new_.extend_from_slice(b"{\"enabled\":true}");
+        fs::write(&path, &old).unwrap();
+
+        let forward = Delta::new().generate_delta(&old, &new_);
+        let reverse = forward
+            .apply_with_reverse(Cursor::new(&old), path.clone())
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), new_);
+        assert_eq!(reverse.target_len(), old.len() as u64);
+        assert!(reverse.stats().bytes_copied >= 35_000);
+
+        let mut out = Vec::new();
+        reverse.apply_to(&mut Cursor::new(&new_), &mut out).unwrap();
+        pretty_assertions::assert_eq!(out, old);
+
+        let round_trip = Delta::compose(&forward, &reverse);
+        let mut out = Vec::new();
+        round_trip
+            .apply_to(&mut Cursor::new(&old), &mut out)
+            .unwrap();
+        pretty_assertions::assert_eq!(out, old);
+    }
+
+    #[test]
+    fn test_stats_and_dump() {
+        let delta = sample_delta();
+        let stats = delta.stats();
+        assert_eq!(
+            stats.bytes_copied + stats.bytes_inserted,
+            delta.target_len()
+        );
+        assert!(stats.copy_ops > 0 && stats.insert_ops > 0);
+        assert!(stats.ratio() > 0.0 && stats.ratio() < 0.5);
+
+        let dump = delta.to_string();
+        assert_eq!(dump.lines().count(), 1 + stats.copy_ops + stats.insert_ops);
+        assert!(dump.contains("  copy    offset=0 len="));
+        assert!(dump.contains("  insert  len="));
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let delta = sample_delta();
+        let encoded = delta.encode();
+        assert_eq!(&encoded[..4], &MAGIC);
+        pretty_assertions::assert_eq!(Delta::decode(&encoded), Ok(delta));
+    }
+
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    #[test]
+    fn test_compressed_roundtrip() {
+        let base = vec![0u8; 8192];
+        let new_: Vec<u8> = b"{\"setting\": true}\n".repeat(2000);
+        let delta = Delta::new().generate_delta(&base, &new_);
+
+        let codecs = [
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+        ];
+        for compression in codecs {
+            let encoded = delta.encode_with(compression).unwrap();
+            assert!(encoded.compression_ratio() < 0.5, "{compression:?}");
+            assert!(encoded.bytes.len() < delta.encode().len());
+            pretty_assertions::assert_eq!(Delta::decode(&encoded.bytes), Ok(delta.clone()));
+
+            let truncated = &encoded.bytes[..encoded.bytes.len() - 1];
+            assert_eq!(Delta::decode(truncated), Err(WireError::Truncated));
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_input() {
+        let encoded = sample_delta().encode();
+
+        assert_eq!(Delta::decode(b"nope"), Err(WireError::BadMagic));
+        assert_eq!(Delta::decode(&encoded[..2]), Err(WireError::BadMagic));
+
+        let mut bad_version = encoded.clone();
+        bad_version[4] = FORMAT_VERSION + 1;
+        assert_eq!(
+            Delta::decode(&bad_version),
+            Err(WireError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+
+        for cut in 5..encoded.len() {
+            assert_eq!(
+                Delta::decode(&encoded[..cut]),
+                Err(WireError::Truncated),
+                "cut at {cut}"
+            );
+        }
+
+        let mut unknown_codec = encoded.clone();
+        unknown_codec[5] = 0b11;
+        assert_eq!(
+            Delta::decode(&unknown_codec),
+            Err(WireError::UnsupportedCompression(0b11))
+        );
+
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert_eq!(Delta::decode(&trailing), Err(WireError::TrailingBytes(1)));
+
+        let mut unknown_op = Vec::from(MAGIC);
+        unknown_op.extend_from_slice(&[FORMAT_VERSION, 0, 0]);
+        unknown_op.extend_from_slice(&[0; 32]);
+        unknown_op.push(0);
+        unknown_op.extend_from_slice(&[0; 32]);
+        unknown_op.extend_from_slice(&[1, 0x7f]);
+        assert_eq!(
+            Delta::decode(&unknown_op),
+            Err(WireError::UnknownOperation(0x7f))
+        );
+
+        // copies past the end of memory, alone or together, would overflow
+        // when the delta is applied or composed
+        let copies = |ops: &[(u64, u64)]| {
+            let mut bytes = Vec::from(MAGIC);
+            bytes.extend_from_slice(&[FORMAT_VERSION, 0, 0]);
+            bytes.extend_from_slice(&[0; 32]);
+            bytes.push(StrongHashKind::default().id());
+            bytes.extend_from_slice(&[0; 32]);
+            write_varint(&mut bytes, ops.len() as u64);
+            for &(offset, len) in ops {
+                bytes.push(TAG_COPY);
+                write_varint(&mut bytes, offset);
+                write_varint(&mut bytes, len);
+            }
+            bytes
+        };
+        let max = usize::MAX as u64;
+        assert_eq!(
+            Delta::decode(&copies(&[(max, 10)])),
+            Err(WireError::OperationsOverflow)
+        );
+        assert_eq!(
+            Delta::decode(&copies(&[(0, max), (0, 1)])),
+            Err(WireError::OperationsOverflow)
+        );
+        assert!(Delta::decode(&copies(&[(max - 10, 10)])).is_ok());
+    }
+
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    #[test]
+    fn test_decode_rejects_huge_literal_length() {
+        let compression = [
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+        ][0];
+        let header = |insert_len: u64, raw_len: u64| {
+            let mut bytes = Vec::from(MAGIC);
+            bytes.extend_from_slice(&[FORMAT_VERSION, compression.id(), 0]);
+            bytes.extend_from_slice(&[0; 32]);
+            bytes.push(StrongHashKind::default().id());
+            bytes.extend_from_slice(&[0; 32]);
+            bytes.extend_from_slice(&[1, TAG_INSERT]);
+            write_varint(&mut bytes, insert_len);
+            write_varint(&mut bytes, raw_len);
+            write_varint(&mut bytes, 0);
+            bytes
+        };
+
+        // a length that does not match the inserts
+        assert!(matches!(
+            Delta::decode(&header(5, 1 << 40)),
+            Err(WireError::CorruptLiterals(_))
+        ));
+        // a matching length, but more than we are willing to allocate
+        assert!(matches!(
+            Delta::decode(&header(1 << 40, 1 << 40)),
+            Err(WireError::CorruptLiterals(_))
+        ));
+    }
 }
@@ -0,0 +1,260 @@
+//! Passphrase-based authenticated encryption with XChaCha20-Poly1305.
+//!
+//! Plugin settings often contain API tokens, so anything that leaves this
+//! machine through a shared folder, or sits in a backup, can be sealed with an
+//! [`EncryptionKey`]. The key is derived from a passphrase with Argon2id and a
+//! random salt and kept in the key store, so the passphrase is only needed
+//! once per machine. Peers that exchange sealed envelopes must derive their
+//! key from the same passphrase and salt.
+//!
+//! Envelope layout:
+//!
+//! ```text
+//! magic       4 bytes   b"OSEN"
+//! version     u8        ENVELOPE_VERSION
+//! kind        u8        PayloadKind
+//! salt        16 bytes  salt the key was derived with
+//! nonce       24 bytes  random XChaCha20 nonce
+//! ciphertext  rest of the envelope, including the 16 byte Poly1305 tag
+//! ```
+//!
+//! Everything before the nonce is authenticated as associated data, so an
+//! envelope cannot be relabelled as another kind of payload.
+
+use std::{fmt, fs, io};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    Key, XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, KeyInit, Payload},
+};
+use color_eyre::eyre::{Result, eyre};
+use rand::{RngCore, rngs::OsRng};
+use tracing::{debug, info};
+
+use super::identity::{AuthError, KeyStore, check_private, create_private_dir, write_private};
+use super::wire::{PayloadKind, WireError, WireReader};
+
+const MAGIC: &[u8; 4] = b"OSEN";
+pub const ENVELOPE_VERSION: u8 = 1;
+
+pub const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+const ENCRYPTION_KEY_FILE: &str = "encryption.key";
+
+#[derive(Clone)]
+pub struct EncryptionKey {
+    salt: [u8; SALT_LEN],
+    key: Key,
+}
+
+impl EncryptionKey {
+    /// Derives a key from `passphrase` with Argon2id. This is deliberately
+    /// slow; derive once and keep the result in the [`KeyStore`].
+    pub fn from_passphrase(passphrase: &str, salt: [u8; SALT_LEN]) -> Result<Self> {
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| eyre!("could not derive encryption key: {e}"))?;
+        Ok(Self { salt, key })
+    }
+
+    pub fn generate_salt() -> [u8; SALT_LEN] {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        salt
+    }
+
+    pub fn salt(&self) -> [u8; SALT_LEN] {
+        self.salt
+    }
+
+    /// Encrypts `plaintext` into an envelope labelled with `kind`.
+    pub fn seal(&self, kind: PayloadKind, plaintext: &[u8]) -> Vec<u8> {
+        let mut out = self.header(kind);
+        let aad_len = out.len();
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(&self.key)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &out[..aad_len],
+                },
+            )
+            .expect("encryption only fails for absurdly large inputs");
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        out
+    }
+
+    /// Decrypts an envelope made by [`EncryptionKey::seal`], checking that it
+    /// holds a `kind` payload and has not been modified.
+    pub fn open(&self, envelope: &[u8], kind: PayloadKind) -> Result<Vec<u8>, AuthError> {
+        let mut reader = WireReader::new(envelope);
+        if reader.read_bytes(MAGIC.len())? != MAGIC {
+            return Err(WireError::BadMagic.into());
+        }
+        let version = reader.read_u8()?;
+        if version != ENVELOPE_VERSION {
+            return Err(WireError::UnsupportedVersion(version).into());
+        }
+        let found = PayloadKind::from_id(reader.read_u8()?)?;
+        if found != kind {
+            return Err(AuthError::UnexpectedKind {
+                expected: kind,
+                found,
+            });
+        }
+        if reader.read_array::<SALT_LEN>()? != self.salt {
+            return Err(AuthError::KeyMismatch);
+        }
+        let nonce: [u8; NONCE_LEN] = reader.read_array()?;
+        let ciphertext = reader.read_bytes(reader.remaining())?;
+
+        let plaintext = XChaCha20Poly1305::new(&self.key)
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &self.header(kind),
+                },
+            )
+            .map_err(|_| AuthError::DecryptionFailed)?;
+        debug!(?kind, len = plaintext.len(), "Opened envelope");
+        Ok(plaintext)
+    }
+
+    fn header(&self, kind: PayloadKind) -> Vec<u8> {
+        let mut header = Vec::with_capacity(MAGIC.len() + 2 + SALT_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(ENVELOPE_VERSION);
+        header.push(kind.id());
+        header.extend_from_slice(&self.salt);
+        header
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("salt", &self.salt)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyStore {
+    /// The key derived by [`KeyStore::set_passphrase`], if one was set.
+    pub fn encryption_key(&self) -> Result<Option<EncryptionKey>> {
+        let path = self.dir.join(ENCRYPTION_KEY_FILE);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if bytes.len() != SALT_LEN + KEY_LEN {
+            return Err(eyre!("{} is not an encryption key", path.display()));
+        }
+        check_private(&path)?;
+        let (salt, key) = bytes.split_at(SALT_LEN);
+        Ok(Some(EncryptionKey {
+            salt: salt.try_into().expect("split at SALT_LEN"),
+            key: *Key::from_slice(key),
+        }))
+    }
+
+    /// Derives the encryption key from `passphrase` and stores it, replacing
+    /// any previous key. Pass the salt printed on another machine to share its
+    /// key, or `None` to start with a fresh one.
+    pub fn set_passphrase(
+        &self,
+        passphrase: &str,
+        salt: Option<[u8; SALT_LEN]>,
+    ) -> Result<EncryptionKey> {
+        let key = EncryptionKey::from_passphrase(
+            passphrase,
+            salt.unwrap_or_else(EncryptionKey::generate_salt),
+        )?;
+        create_private_dir(&self.dir)?;
+        let path = self.dir.join(ENCRYPTION_KEY_FILE);
+        let staged = self.dir.join(format!("{ENCRYPTION_KEY_FILE}.new"));
+        match fs::remove_file(&staged) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let mut contents = key.salt.to_vec();
+        contents.extend_from_slice(&key.key);
+        write_private(&staged, &contents)?;
+        fs::rename(&staged, &path)?;
+        info!(salt = ?key.salt, "Stored a new encryption key");
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let salt = EncryptionKey::generate_salt();
+        let key = EncryptionKey::from_passphrase("correct horse", salt).unwrap();
+        let secret = br#"{"apiKey":"sk-123"}"#;
+
+        let envelope = key.seal(PayloadKind::Snapshot, secret);
+        assert!(!envelope.windows(secret.len()).any(|w| w == secret));
+        assert_eq!(key.open(&envelope, PayloadKind::Snapshot).unwrap(), secret);
+        assert_eq!(
+            key.open(&envelope, PayloadKind::Delta),
+            Err(AuthError::UnexpectedKind {
+                expected: PayloadKind::Delta,
+                found: PayloadKind::Snapshot
+            })
+        );
+
+        let same = EncryptionKey::from_passphrase("correct horse", salt).unwrap();
+        assert_eq!(same.open(&envelope, PayloadKind::Snapshot).unwrap(), secret);
+        let wrong = EncryptionKey::from_passphrase("battery staple", salt).unwrap();
+        assert_eq!(
+            wrong.open(&envelope, PayloadKind::Snapshot),
+            Err(AuthError::DecryptionFailed)
+        );
+
+        let mut relabelled = envelope.clone();
+        relabelled[5] = PayloadKind::Delta.id();
+        assert_eq!(
+            key.open(&relabelled, PayloadKind::Delta),
+            Err(AuthError::DecryptionFailed)
+        );
+    }
+
+    #[test]
+    fn test_key_store_keeps_derived_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = KeyStore::new(dir.path().join("keys"));
+        assert!(store.encryption_key().unwrap().is_none());
+
+        let key = store.set_passphrase("correct horse", None).unwrap();
+        let envelope = key.seal(PayloadKind::Delta, b"delta");
+        let loaded = store.encryption_key().unwrap().unwrap();
+        assert_eq!(
+            loaded.open(&envelope, PayloadKind::Delta).unwrap(),
+            b"delta"
+        );
+
+        let other = store.set_passphrase("correct horse", None).unwrap();
+        assert_eq!(
+            other.open(&envelope, PayloadKind::Delta),
+            Err(AuthError::KeyMismatch)
+        );
+        let shared = store
+            .set_passphrase("correct horse", Some(key.salt()))
+            .unwrap();
+        assert_eq!(
+            shared.open(&envelope, PayloadKind::Delta).unwrap(),
+            b"delta"
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! Strong block hashes used to confirm matches found by the weak rolling hash.
+//!
+//! [`Xxh3`] is fast and good enough when both files live on this machine.
+//! [`Blake3`] is cryptographic, so a peer cannot craft a block that collides
+//! with one of ours; use it when signatures or deltas cross machines.
+
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::xxh3_128;
+
+/// Strong hash of one block. BLAKE3 digests are truncated to their first
+/// 128 bits, which keeps signature entries small.
+pub type StrongHash = u128;
+
+pub trait StrongHasher: Sync {
+    fn kind(&self) -> StrongHashKind;
+    fn digest(&self, data: &[u8]) -> StrongHash;
+}
+
+pub struct Xxh3;
+
+impl StrongHasher for Xxh3 {
+    fn kind(&self) -> StrongHashKind {
+        StrongHashKind::Xxh3
+    }
+
+    fn digest(&self, data: &[u8]) -> StrongHash {
+        xxh3_128(data)
+    }
+}
+
+pub struct Blake3;
+
+impl StrongHasher for Blake3 {
+    fn kind(&self) -> StrongHashKind {
+        StrongHashKind::Blake3
+    }
+
+    fn digest(&self, data: &[u8]) -> StrongHash {
+        let hash = blake3::hash(data);
+        let (head, _) = hash.as_bytes().split_first_chunk::<16>().unwrap();
+        u128::from_le_bytes(*head)
+    }
+}
+
+/// Which [`StrongHasher`] a signature was built with. Stored in signatures
+/// and deltas so the two sides can tell they agree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StrongHashKind {
+    #[default]
+    Xxh3,
+    Blake3,
+}
+
+impl StrongHashKind {
+    pub fn hasher(self) -> &'static dyn StrongHasher {
+        match self {
+            StrongHashKind::Xxh3 => &Xxh3,
+            StrongHashKind::Blake3 => &Blake3,
+        }
+    }
+
+    /// Identifier used in binary encodings.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            StrongHashKind::Xxh3 => 0,
+            StrongHashKind::Blake3 => 1,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(StrongHashKind::Xxh3),
+            1 => Some(StrongHashKind::Blake3),
+            _ => None,
+        }
+    }
+}
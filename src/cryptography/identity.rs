@@ -0,0 +1,442 @@
+//! Ed25519 identities and signed envelopes.
+//!
+//! Plugins are executable JavaScript, so a syncer must not write anything it
+//! received from another machine unless a peer it trusts produced it. Every
+//! syncer has an [`Identity`] whose secret key never leaves its data dir.
+//! Outgoing deltas and signature tables are wrapped in a signed envelope, and
+//! the receiver checks the envelope against its [`TrustedPeers`] before it
+//! even decodes the payload.
+//!
+//! Envelope layout:
+//!
+//! ```text
+//! magic      4 bytes   b"OSSG"
+//! version    u8        ENVELOPE_VERSION
+//! kind       u8        PayloadKind
+//! signer     32 bytes  Ed25519 public key
+//! signature  64 bytes  Ed25519 signature over magic, version, kind, signer
+//!                      and payload
+//! payload    rest of the envelope
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{Result, eyre};
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use super::wire::{PayloadKind, WireError, WireReader};
+use crate::atomic::write_atomic;
+use crate::logging::get_data_dir;
+
+const MAGIC: &[u8; 4] = b"OSSG";
+pub const ENVELOPE_VERSION: u8 = 1;
+
+const IDENTITY_FILE: &str = "identity.key";
+const TRUSTED_PEERS_FILE: &str = "trusted_peers.json";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// The envelope or its payload could not be parsed.
+    Malformed(WireError),
+    /// The envelope carries a different kind of payload than expected.
+    UnexpectedKind {
+        expected: PayloadKind,
+        found: PayloadKind,
+    },
+    /// The envelope was signed by a key that is not in the trusted peers.
+    UntrustedKey(PeerKey),
+    /// The signature does not match the envelope contents.
+    BadSignature,
+    /// The signature is valid but the payload is not.
+    InvalidPayload(String),
+    /// The envelope was encrypted with a key derived from a different salt.
+    KeyMismatch,
+    /// Decryption failed: wrong key, or the ciphertext was tampered with.
+    DecryptionFailed,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Malformed(e) => write!(f, "malformed envelope: {e}"),
+            AuthError::UnexpectedKind { expected, found } => {
+                write!(f, "expected a {expected:?} envelope, got {found:?}")
+            }
+            AuthError::UntrustedKey(key) => write!(f, "envelope signed by untrusted key {key}"),
+            AuthError::BadSignature => write!(f, "envelope signature is invalid"),
+            AuthError::InvalidPayload(reason) => write!(f, "invalid signed payload: {reason}"),
+            AuthError::KeyMismatch => {
+                write!(f, "envelope was encrypted with a different passphrase salt")
+            }
+            AuthError::DecryptionFailed => {
+                write!(f, "envelope could not be decrypted with this key")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<WireError> for AuthError {
+    fn from(e: WireError) -> Self {
+        AuthError::Malformed(e)
+    }
+}
+
+/// Public half of an [`Identity`]. Written as 64 hex digits, which is how
+/// peers exchange keys and how they appear in the trusted peers file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PeerKey([u8; 32]);
+
+impl PeerKey {
+    pub fn from_bytes(bytes: &[u8; 32]) -> Option<Self> {
+        VerifyingKey::from_bytes(bytes).ok()?;
+        Some(PeerKey(*bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey::from_bytes(&self.0).expect("checked when the key was created")
+    }
+}
+
+impl fmt::Display for PeerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.as_bytes() {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PeerKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(format!("expected 64 hex digits, got {s:?}"));
+        }
+        let mut bytes = [0u8; 32];
+        for (byte, pair) in bytes.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).expect("input is ascii");
+            *byte = u8::from_str_radix(pair, 16).map_err(|e| format!("{s:?}: {e}"))?;
+        }
+        PeerKey::from_bytes(&bytes).ok_or_else(|| format!("{s} is not an Ed25519 public key"))
+    }
+}
+
+impl TryFrom<String> for PeerKey {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PeerKey> for String {
+    fn from(key: PeerKey) -> Self {
+        key.to_string()
+    }
+}
+
+/// This syncer's signing key.
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    pub fn public_key(&self) -> PeerKey {
+        PeerKey(self.key.verifying_key().to_bytes())
+    }
+
+    /// Wraps `payload` in an envelope signed with this identity.
+    pub fn sign(&self, kind: PayloadKind, payload: &[u8]) -> Vec<u8> {
+        let signer = self.public_key();
+        let signature = self.key.sign(&signed_message(kind, &signer, payload));
+
+        let mut out = Vec::with_capacity(MAGIC.len() + 2 + 32 + 64 + payload.len());
+        out.extend_from_slice(MAGIC);
+        out.push(ENVELOPE_VERSION);
+        out.push(kind.id());
+        out.extend_from_slice(signer.as_bytes());
+        out.extend_from_slice(&signature.to_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("public_key", &self.public_key())
+            .finish_non_exhaustive()
+    }
+}
+
+fn signed_message(kind: PayloadKind, signer: &PeerKey, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(MAGIC.len() + 2 + 32 + payload.len());
+    message.extend_from_slice(MAGIC);
+    message.push(ENVELOPE_VERSION);
+    message.push(kind.id());
+    message.extend_from_slice(signer.as_bytes());
+    message.extend_from_slice(payload);
+    message
+}
+
+/// Public keys whose envelopes we accept, by a name chosen by the user.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustedPeers {
+    peers: BTreeMap<String, PeerKey>,
+}
+
+impl TrustedPeers {
+    pub fn insert(&mut self, name: impl Into<String>, key: PeerKey) {
+        self.peers.insert(name.into(), key);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<PeerKey> {
+        self.peers.remove(name)
+    }
+
+    /// Name the key was trusted under, if it is trusted at all.
+    pub fn name_of(&self, key: &PeerKey) -> Option<&str> {
+        self.peers
+            .iter()
+            .find(|(_, k)| *k == key)
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &PeerKey)> {
+        self.peers.iter().map(|(name, key)| (name.as_str(), key))
+    }
+
+    /// Checks that `envelope` holds a `kind` payload signed by a trusted peer
+    /// and returns the payload.
+    pub fn verify<'a>(&self, envelope: &'a [u8], kind: PayloadKind) -> Result<&'a [u8], AuthError> {
+        let mut reader = WireReader::new(envelope);
+        if reader.read_bytes(MAGIC.len())? != MAGIC {
+            return Err(WireError::BadMagic.into());
+        }
+        let version = reader.read_u8()?;
+        if version != ENVELOPE_VERSION {
+            return Err(WireError::UnsupportedVersion(version).into());
+        }
+        let found = PayloadKind::from_id(reader.read_u8()?)?;
+        if found != kind {
+            return Err(AuthError::UnexpectedKind {
+                expected: kind,
+                found,
+            });
+        }
+        let signer = PeerKey::from_bytes(&reader.read_array()?).ok_or(AuthError::BadSignature)?;
+        let signature = ed25519_dalek::Signature::from_bytes(&reader.read_array()?);
+        let payload = reader.read_bytes(reader.remaining())?;
+
+        let Some(name) = self.name_of(&signer) else {
+            return Err(AuthError::UntrustedKey(signer));
+        };
+        signer
+            .verifying_key()
+            .verify_strict(&signed_message(kind, &signer, payload), &signature)
+            .map_err(|_| AuthError::BadSignature)?;
+        debug!(peer = name, ?kind, len = payload.len(), "Verified envelope");
+        Ok(payload)
+    }
+}
+
+/// Our identity and trusted peers, kept in `keys` under the data dir.
+#[derive(Debug, Clone)]
+pub struct KeyStore {
+    pub(super) dir: PathBuf,
+}
+
+impl Default for KeyStore {
+    fn default() -> Self {
+        Self::new(get_data_dir().join("keys"))
+    }
+}
+
+impl KeyStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Loads this syncer's identity, generating one on first use. The secret
+    /// key file is only readable by the owner.
+    pub fn identity(&self) -> Result<Identity> {
+        let path = self.dir.join(IDENTITY_FILE);
+        match fs::read(&path) {
+            Ok(bytes) => {
+                let secret: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| eyre!("{} is not an Ed25519 secret key", path.display()))?;
+                check_private(&path)?;
+                Ok(Identity {
+                    key: SigningKey::from_bytes(&secret),
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Identity::generate();
+                create_private_dir(&self.dir)?;
+                write_private(&path, identity.key.as_bytes())?;
+                info!(public_key = %identity.public_key(), "Generated a new identity");
+                Ok(identity)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn trusted_peers(&self) -> Result<TrustedPeers> {
+        match fs::read(self.dir.join(TRUSTED_PEERS_FILE)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(TrustedPeers::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save_trusted_peers(&self, peers: &TrustedPeers) -> Result<()> {
+        create_private_dir(&self.dir)?;
+        write_atomic(&self.dir.join(TRUSTED_PEERS_FILE), |out| {
+            serde_json::to_writer_pretty(out, peers)?;
+            Ok(())
+        })
+    }
+
+    /// Adds `key` to the trusted peers under `name`, replacing any key that
+    /// was trusted under that name before.
+    pub fn trust(&self, name: &str, key: PeerKey) -> Result<()> {
+        let mut peers = self.trusted_peers()?;
+        peers.insert(name, key);
+        self.save_trusted_peers(&peers)?;
+        info!(peer = name, %key, "Trusting peer");
+        Ok(())
+    }
+}
+
+pub(super) fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+/// Creates `path` with owner-only permissions. Fails if it already exists, so
+/// two syncers starting at once cannot overwrite each other's key.
+pub(super) fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+pub(super) fn check_private(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            warn!(
+                path = ?path,
+                mode = format!("{:o}", mode & 0o777),
+                "Secret key is readable by other users"
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_trusted_signers_are_accepted() {
+        let ours = Identity::generate();
+        let stranger = Identity::generate();
+        let mut peers = TrustedPeers::default();
+        peers.insert("laptop", ours.public_key());
+
+        let envelope = ours.sign(PayloadKind::Delta, b"delta bytes");
+        assert_eq!(
+            peers.verify(&envelope, PayloadKind::Delta),
+            Ok(&b"delta bytes"[..])
+        );
+        assert_eq!(
+            peers.verify(&envelope, PayloadKind::SignatureTable),
+            Err(AuthError::UnexpectedKind {
+                expected: PayloadKind::SignatureTable,
+                found: PayloadKind::Delta
+            })
+        );
+
+        let forged = stranger.sign(PayloadKind::Delta, b"delta bytes");
+        assert_eq!(
+            peers.verify(&forged, PayloadKind::Delta),
+            Err(AuthError::UntrustedKey(stranger.public_key()))
+        );
+
+        let mut tampered = envelope.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            peers.verify(&tampered, PayloadKind::Delta),
+            Err(AuthError::BadSignature)
+        );
+        assert_eq!(
+            peers.verify(&envelope[..40], PayloadKind::Delta),
+            Err(AuthError::Malformed(WireError::Truncated))
+        );
+    }
+
+    #[test]
+    fn test_key_store_persists_identity_and_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = KeyStore::new(dir.path().join("keys"));
+
+        let identity = store.identity().unwrap();
+        assert_eq!(
+            store.identity().unwrap().public_key(),
+            identity.public_key()
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = dir.path().join("keys").join(IDENTITY_FILE);
+            let mode = fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let peer = Identity::generate().public_key();
+        let parsed: PeerKey = peer.to_string().parse().unwrap();
+        assert_eq!(parsed, peer);
+        store.trust("desktop", peer).unwrap();
+        let peers = store.trusted_peers().unwrap();
+        assert_eq!(peers.name_of(&peer), Some("desktop"));
+        assert_eq!(peers.name_of(&identity.public_key()), None);
+    }
+}
@@ -1,2 +1,10 @@
+pub mod cache;
+pub mod chunking;
+pub mod compression;
 pub mod delta;
+pub mod encryption;
+pub mod hashing;
+pub mod identity;
 pub mod signatures;
+mod stream;
+pub mod wire;
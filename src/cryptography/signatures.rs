@@ -1,57 +1,307 @@
-use std::{collections::HashMap, io::Write};
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+};
 
+use color_eyre::eyre::{Result, eyre};
 use fbuzhash::BuzHash;
-use xxhash_rust::xxh3::xxh3_64;
+use serde::{Deserialize, Serialize};
 
-pub const BLOCK_SIZE: usize = 4096;
+use super::chunking::{CdcParams, ChunkingStrategy};
+use super::encryption::EncryptionKey;
+use super::hashing::{StrongHash, StrongHashKind};
+use super::identity::{AuthError, Identity, TrustedPeers};
+use super::stream::ReadWindow;
+use super::wire::PayloadKind;
 
-#[derive(Debug, Default)]
+/// Limits for the block size picked by [`BlockSizeBounds::block_size_for`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSizeBounds {
+    min: usize,
+    max: usize,
+}
+
+impl Default for BlockSizeBounds {
+    fn default() -> Self {
+        Self {
+            min: 64,
+            max: 128 * 1024,
+        }
+    }
+}
+
+impl BlockSizeBounds {
+    /// Bounds from `min` to `max` bytes. Blocks cannot be empty, so `min`
+    /// must be at least 1, and `max` no smaller than `min`.
+    pub fn new(min: usize, max: usize) -> Result<Self> {
+        if min == 0 {
+            return Err(eyre!("minimum block size must be at least 1"));
+        }
+        if max < min {
+            return Err(eyre!("maximum block size {max} is below the minimum {min}"));
+        }
+        Ok(Self { min, max })
+    }
+
+    pub fn min(&self) -> usize {
+        self.min
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Block size for a file of `file_len` bytes: roughly its square root, so
+    /// the number of signature entries and the size of each block grow at the
+    /// same rate. Rounded up to a multiple of 16 and clamped to the bounds.
+    pub fn block_size_for(&self, file_len: u64) -> usize {
+        let root = (file_len as f64).sqrt().ceil() as usize;
+        root.next_multiple_of(16).clamp(self.min, self.max)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Signature {
+    strategy: ChunkingStrategy,
+    hash: StrongHashKind,
+    #[serde(skip)]
+    bounds: BlockSizeBounds,
+    block_size: usize,
     entries: HashMap<u32, Vec<SigEntry>>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SigEntry {
-    pub strong: u64,
+    pub strong: StrongHash,
     pub offset: usize,
     pub len: usize,
 }
 
 impl Signature {
     pub fn new() -> Self {
+        Self::with_strategy(ChunkingStrategy::default())
+    }
+
+    pub fn with_strategy(strategy: ChunkingStrategy) -> Self {
         Self {
+            strategy,
+            hash: StrongHashKind::default(),
+            bounds: BlockSizeBounds::default(),
+            block_size: 0,
             entries: HashMap::new(),
         }
     }
 
-    pub fn build(&mut self, base: &[u8]) -> &Self {
-        let mut sigs: HashMap<u32, Vec<SigEntry>> = HashMap::new();
-        let mut offset = 0usize;
+    pub fn with_bounds(mut self, bounds: BlockSizeBounds) -> Self {
+        self.bounds = bounds;
+        self
+    }
 
-        while offset < base.len() {
-            let len = BLOCK_SIZE.min(base.len() - offset);
-            let block = &base[offset..offset + len];
+    pub fn with_hash(mut self, hash: StrongHashKind) -> Self {
+        self.hash = hash;
+        self
+    }
 
-            // Use a BuzHash sized to the block length
-            let mut bh = BuzHash::new(len as u32);
-            // write_all (Write trait) feeds all bytes and updates internal rolling buffer/state
-            bh.write_all(block).unwrap();
-            let weak = bh.sum32(); // u32 weak hash
+    pub fn build(&mut self, base: &[u8]) -> &Self {
+        self.build_from(base, base.len() as u64)
+            .expect("reading from a slice cannot fail")
+    }
 
-            let strong = xxh3_64(block); // verification hash (u64)
+    /// Streaming form of [`Signature::build`]. `len` is the length of the
+    /// base, which decides the block size before any of it is read.
+    pub fn build_from(&mut self, base: impl Read, len: u64) -> io::Result<&Self> {
+        self.block_size = self.bounds.block_size_for(len);
+        let mut sigs: HashMap<u32, Vec<SigEntry>> = HashMap::new();
+        let mut offset = 0usize;
+        let mut input = ReadWindow::new(base);
+        let params = self.cdc_params();
+        let hasher = self.hash.hasher();
 
-            sigs.entry(weak).or_default().push(SigEntry {
-                strong,
+        loop {
+            let available = match self.strategy {
+                ChunkingStrategy::Fixed => input.fill(self.block_size)?,
+                ChunkingStrategy::ContentDefined => input.fill(params.max)?,
+            };
+            if available.is_empty() {
+                break;
+            }
+            let block_len = match self.strategy {
+                ChunkingStrategy::Fixed => self.block_size.min(available.len()),
+                ChunkingStrategy::ContentDefined => params.cut(available),
+            };
+            let block = &available[..block_len];
+            sigs.entry(weak_hash(block)).or_default().push(SigEntry {
+                strong: hasher.digest(block), // verification hash
                 offset,
-                len,
+                len: block_len,
             });
-
-            offset += len;
+            offset += block_len;
+            input.consume(block_len);
         }
         self.entries = sigs;
-        return self;
+        Ok(self)
     }
     pub fn get_entries(&self) -> &HashMap<u32, Vec<SigEntry>> {
         &self.entries
     }
+
+    pub fn strategy(&self) -> ChunkingStrategy {
+        self.strategy
+    }
+
+    /// The strong hash the entries were built with. Blocks of the new file
+    /// must be hashed the same way to be matched against this signature.
+    pub fn hash_kind(&self) -> StrongHashKind {
+        self.hash
+    }
+
+    /// Digest identifying this exact signature: its parameters and every
+    /// entry. A delta records the fingerprint of the signature it was
+    /// generated against.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        let mut entries: Vec<(&u32, &SigEntry)> = self
+            .entries
+            .iter()
+            .flat_map(|(weak, entries)| entries.iter().map(move |e| (weak, e)))
+            .collect();
+        entries.sort_by_key(|(_, e)| e.offset);
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&[self.strategy as u8, self.hash.id()]);
+        hasher.update(&(self.block_size as u64).to_le_bytes());
+        for (weak, entry) in entries {
+            hasher.update(&(entry.offset as u64).to_le_bytes());
+            hasher.update(&(entry.len as u64).to_le_bytes());
+            hasher.update(&weak.to_le_bytes());
+            hasher.update(&entry.strong.to_le_bytes());
+        }
+        *hasher.finalize().as_bytes()
+    }
+
+    /// Block size chosen from the base's length when the signature was built,
+    /// used by fixed chunking. Zero before [`Signature::build`].
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Chunk size limits used when the strategy is content-defined. The other
+    /// side must cut its data with the same parameters for chunks to match,
+    /// so they do not depend on the length of the file.
+    pub fn cdc_params(&self) -> CdcParams {
+        CdcParams::default()
+    }
+
+    /// Finds a block of the base with the given hashes and length. Identical
+    /// chunks hash identically in every file, so this also answers whether a
+    /// chunk of some other file is already present in this one.
+    pub fn find(&self, weak: u32, strong: StrongHash, len: usize) -> Option<&SigEntry> {
+        self.entries
+            .get(&weak)?
+            .iter()
+            .find(|e| e.len == len && e.strong == strong)
+    }
+
+    /// Serializes the signature and wraps it in an envelope signed by
+    /// `identity`, so a peer can generate a delta against our base.
+    pub fn encode_signed(&self, identity: &Identity) -> Vec<u8> {
+        let payload = serde_json::to_vec(self).expect("signatures always serialize");
+        identity.sign(PayloadKind::SignatureTable, &payload)
+    }
+
+    /// Parses a signature from an envelope made by
+    /// [`Signature::encode_signed`], if a trusted peer signed it.
+    pub fn decode_signed(envelope: &[u8], peers: &TrustedPeers) -> Result<Self, AuthError> {
+        let payload = peers.verify(envelope, PayloadKind::SignatureTable)?;
+        serde_json::from_slice(payload).map_err(|e| AuthError::InvalidPayload(e.to_string()))
+    }
+
+    /// Signs the signature like [`Signature::encode_signed`] and encrypts the
+    /// signed envelope.
+    pub fn encode_sealed(&self, identity: &Identity, key: &EncryptionKey) -> Vec<u8> {
+        key.seal(PayloadKind::SignatureTable, &self.encode_signed(identity))
+    }
+
+    /// Decrypts and verifies an envelope made by [`Signature::encode_sealed`].
+    pub fn decode_sealed(
+        envelope: &[u8],
+        key: &EncryptionKey,
+        peers: &TrustedPeers,
+    ) -> Result<Self, AuthError> {
+        let signed = key.open(envelope, PayloadKind::SignatureTable)?;
+        Self::decode_signed(&signed, peers)
+    }
+}
+
+/// Weak rolling hash of a whole block, as used for the signature lookup table.
+pub fn weak_hash(block: &[u8]) -> u32 {
+    // Use a BuzHash sized to the block length
+    let mut bh = BuzHash::new(block.len() as u32);
+    // write_all (Write trait) feeds all bytes and updates internal rolling buffer/state
+    bh.write_all(block).unwrap();
+    bh.sum32()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_size_grows_with_file() {
+        let bounds = BlockSizeBounds::default();
+        assert_eq!(bounds.block_size_for(0), bounds.min());
+        assert_eq!(bounds.block_size_for(300), bounds.min());
+        assert_eq!(bounds.block_size_for(1 << 20), 1024);
+        assert_eq!(bounds.block_size_for(20_000_000), 4480);
+        assert_eq!(bounds.block_size_for(u64::MAX), bounds.max());
+    }
+
+    #[test]
+    fn test_invalid_block_size_bounds_are_rejected() {
+        // an empty file would get empty blocks
+        assert!(BlockSizeBounds::new(0, 1024).is_err());
+        assert!(BlockSizeBounds::new(128, 64).is_err());
+        let bounds = BlockSizeBounds::new(1, 1).unwrap();
+        assert_eq!(bounds.block_size_for(0), 1);
+        let mut sigs = Signature::new().with_bounds(bounds);
+        sigs.build(&[]);
+        assert_eq!(sigs.block_size(), 1);
+        assert!(sigs.get_entries().is_empty());
+    }
+
+    #[test]
+    fn test_content_defined_chunks_do_not_depend_on_file_length() {
+        let data: Vec<u8> = (0..1u32 << 20)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let mut small = Signature::with_strategy(ChunkingStrategy::ContentDefined);
+        small.build(&data[..16 * 1024]);
+        let mut large = Signature::with_strategy(ChunkingStrategy::ContentDefined);
+        large.build(&data);
+        assert_ne!(small.block_size(), large.block_size());
+        assert_eq!(small.cdc_params(), large.cdc_params());
+
+        // chunks cut from the same content match across both files
+        let first = small
+            .get_entries()
+            .values()
+            .flatten()
+            .find(|e| e.offset == 0)
+            .unwrap();
+        assert!(
+            large
+                .get_entries()
+                .values()
+                .flatten()
+                .any(|e| e.offset == 0 && e.len == first.len && e.strong == first.strong)
+        );
+    }
+
+    #[test]
+    fn test_signature_records_block_size() {
+        let base = vec![1u8; 300];
+        let mut sigs = Signature::new();
+        sigs.build(&base);
+        assert_eq!(sigs.block_size(), 64);
+        assert_eq!(sigs.get_entries().values().map(Vec::len).sum::<usize>(), 5);
+    }
 }
@@ -0,0 +1,114 @@
+//! Bounded read-ahead buffer used to generate deltas from a stream.
+
+use std::io::{self, Read};
+
+/// Bytes requested from the underlying reader beyond what the caller needs,
+/// so sliding the rolling hash one byte at a time does not turn into one
+/// `read` call per byte.
+const READ_AHEAD: usize = 64 * 1024;
+
+/// Sliding view over a reader. Holds at most the largest window asked for
+/// plus [`READ_AHEAD`] bytes, however long the stream is.
+pub(crate) struct ReadWindow<R> {
+    reader: R,
+    buf: Vec<u8>,
+    start: usize,
+    eof: bool,
+    consumed: u64,
+    hasher: Option<blake3::Hasher>,
+}
+
+impl<R: Read> ReadWindow<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            start: 0,
+            eof: false,
+            consumed: 0,
+            hasher: None,
+        }
+    }
+
+    /// A window that also hashes every byte as it is consumed, see
+    /// [`ReadWindow::digest`].
+    pub(crate) fn hashing(reader: R) -> Self {
+        Self {
+            hasher: Some(blake3::Hasher::new()),
+            ..Self::new(reader)
+        }
+    }
+
+    /// Returns the unconsumed bytes, reading until there are at least `want`
+    /// of them or the stream ends.
+    pub(crate) fn fill(&mut self, want: usize) -> io::Result<&[u8]> {
+        if self.buf.len() - self.start < want && !self.eof {
+            self.buf.drain(..self.start);
+            self.start = 0;
+            let target = want + READ_AHEAD;
+            while self.buf.len() < want && !self.eof {
+                let filled = self.buf.len();
+                self.buf.resize(target, 0);
+                match self.reader.read(&mut self.buf[filled..]) {
+                    Ok(0) => {
+                        self.buf.truncate(filled);
+                        self.eof = true;
+                    }
+                    Ok(n) => self.buf.truncate(filled + n),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => self.buf.truncate(filled),
+                    Err(e) => {
+                        self.buf.truncate(filled);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(&self.buf[self.start..])
+    }
+
+    /// Marks the first `n` unconsumed bytes as used.
+    pub(crate) fn consume(&mut self, n: usize) {
+        debug_assert!(self.start + n <= self.buf.len(), "consumed unread bytes");
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&self.buf[self.start..self.start + n]);
+        }
+        self.start += n;
+        self.consumed += n as u64;
+    }
+
+    /// Number of bytes consumed so far and, for a [`ReadWindow::hashing`]
+    /// window, the BLAKE3 digest of them.
+    pub(crate) fn digest(&self) -> (u64, Option<[u8; 32]>) {
+        let hash = self.hasher.as_ref().map(|h| *h.finalize().as_bytes());
+        (self.consumed, hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reader that hands out a few bytes per call, like a slow pipe.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_window_slides_over_short_reads() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut window = ReadWindow::new(Trickle(&data));
+        assert_eq!(&window.fill(10).unwrap()[..10], &data[..10]);
+        window.consume(1);
+        assert_eq!(&window.fill(10).unwrap()[..10], &data[1..11]);
+        assert!(window.fill(250).unwrap().len() >= 250);
+        window.consume(250);
+        assert_eq!(window.fill(10).unwrap(), &data[251..]);
+    }
+}
@@ -0,0 +1,202 @@
+//! Low level helpers shared by the binary encodings in this module.
+//!
+//! Integers that describe offsets and lengths are written as unsigned LEB128
+//! varints: seven bits per byte, least significant group first, with the high
+//! bit set on every byte except the last. A `u64` therefore takes at most ten
+//! bytes.
+
+use std::fmt;
+
+/// Longest possible varint encoding of a `u64`.
+const MAX_VARINT_LEN: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    /// The input does not start with the expected magic bytes.
+    BadMagic,
+    /// The input was written by an encoder version we do not understand.
+    UnsupportedVersion(u8),
+    /// The header sets flag bits that this version does not define.
+    UnsupportedFlags(u8),
+    /// The input ended in the middle of a field.
+    Truncated,
+    /// A varint ran past ten bytes or does not fit in a `u64`.
+    VarintOverflow,
+    /// A length or offset does not fit in this platform's `usize`.
+    LengthOverflow(u64),
+    /// An operation tag that is not part of the format.
+    UnknownOperation(u8),
+    /// Bytes were left over after the last declared field.
+    TrailingBytes(usize),
+    /// The strong hash identifier is not one we know.
+    UnknownHash(u8),
+    /// The payload kind identifier is not one we know.
+    UnknownPayload(u8),
+    /// The literal data uses a codec this build does not include.
+    UnsupportedCompression(u8),
+    /// The literal data could not be decompressed.
+    CorruptLiterals(String),
+    /// A copy ends, or the operations add up to, past the largest `usize`.
+    OperationsOverflow,
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::BadMagic => write!(f, "input does not start with the expected magic bytes"),
+            WireError::UnsupportedVersion(v) => write!(f, "unsupported format version {v}"),
+            WireError::UnsupportedFlags(flags) => {
+                write!(f, "unsupported header flags {flags:#04x}")
+            }
+            WireError::Truncated => write!(f, "input is truncated"),
+            WireError::VarintOverflow => write!(f, "varint is too long"),
+            WireError::LengthOverflow(len) => write!(f, "length {len} does not fit in memory"),
+            WireError::UnknownOperation(tag) => write!(f, "unknown operation tag {tag:#04x}"),
+            WireError::TrailingBytes(n) => write!(f, "{n} unexpected trailing bytes"),
+            WireError::UnknownHash(id) => write!(f, "unknown strong hash {id}"),
+            WireError::UnknownPayload(id) => write!(f, "unknown payload kind {id}"),
+            WireError::UnsupportedCompression(id) => {
+                write!(f, "unsupported compression codec {id}")
+            }
+            WireError::CorruptLiterals(reason) => write!(f, "corrupt literal data: {reason}"),
+            WireError::OperationsOverflow => write!(f, "operations address too many bytes"),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// What an envelope carries. Recorded in the authenticated part of the
+/// envelope, so a payload cannot be passed off as a different kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PayloadKind {
+    Delta,
+    SignatureTable,
+    Snapshot,
+    Object,
+}
+
+impl PayloadKind {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            PayloadKind::Delta => 1,
+            PayloadKind::SignatureTable => 2,
+            PayloadKind::Snapshot => 3,
+            PayloadKind::Object => 4,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Result<Self, WireError> {
+        match id {
+            1 => Ok(PayloadKind::Delta),
+            2 => Ok(PayloadKind::SignatureTable),
+            3 => Ok(PayloadKind::Snapshot),
+            4 => Ok(PayloadKind::Object),
+            id => Err(WireError::UnknownPayload(id)),
+        }
+    }
+}
+
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Cursor over an encoded buffer that turns running out of input into
+/// [`WireError::Truncated`] instead of panicking.
+pub(crate) struct WireReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> WireReader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, WireError> {
+        let byte = *self.buf.get(self.pos).ok_or(WireError::Truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    pub(crate) fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], WireError> {
+        if self.remaining() < len {
+            return Err(WireError::Truncated);
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_array<const N: usize>(&mut self) -> Result<[u8; N], WireError> {
+        let bytes = self.read_bytes(N)?;
+        Ok(bytes.try_into().expect("read_bytes returned N bytes"))
+    }
+
+    pub(crate) fn read_varint(&mut self) -> Result<u64, WireError> {
+        let mut value = 0u64;
+        for i in 0..MAX_VARINT_LEN {
+            let byte = self.read_u8()?;
+            let group = u64::from(byte & 0x7f);
+            // the tenth byte may only carry the single remaining bit of a u64
+            if i == MAX_VARINT_LEN - 1 && group > 1 {
+                return Err(WireError::VarintOverflow);
+            }
+            value |= group << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(WireError::VarintOverflow)
+    }
+
+    /// Reads a varint that is used as an in-memory length or offset.
+    pub(crate) fn read_len(&mut self) -> Result<usize, WireError> {
+        let value = self.read_varint()?;
+        usize::try_from(value).map_err(|_| WireError::LengthOverflow(value))
+    }
+
+    pub(crate) fn finish(self) -> Result<(), WireError> {
+        match self.remaining() {
+            0 => Ok(()),
+            n => Err(WireError::TrailingBytes(n)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint_roundtrip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            let mut reader = WireReader::new(&buf);
+            assert_eq!(reader.read_varint(), Ok(value));
+            assert_eq!(reader.finish(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_varint_rejects_overlong_input() {
+        let buf = [0xff; 11];
+        assert_eq!(
+            WireReader::new(&buf).read_varint(),
+            Err(WireError::VarintOverflow)
+        );
+        assert_eq!(
+            WireReader::new(&[0x80, 0x80]).read_varint(),
+            Err(WireError::Truncated)
+        );
+    }
+}
@@ -1,21 +1,53 @@
+pub mod atomic;
+pub mod conflict;
 pub mod cryptography;
+pub mod logging;
+pub mod merge;
+pub mod merkle;
+pub mod objects;
+pub mod plan;
+pub mod report;
+pub mod snapshot;
+pub mod state;
 pub mod structs;
+pub mod trash;
+use atomic::write_atomic;
 use color_eyre::eyre::Result;
-use cryptography::delta::Delta;
-use ignore::Walk;
+use conflict::{Conflict, ConflictStore};
+use cryptography::cache::SignatureCache;
+use cryptography::delta::{Delta, DeltaError};
+use cryptography::encryption::EncryptionKey;
+use cryptography::identity::TrustedPeers;
+use merge::is_settings;
+use merkle::{TreeCache, hash_file};
 use notify::event::ModifyKind;
 use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
 use notify::{Event, EventKind};
+use objects::ObjectStore;
+use plan::{
+    DeletionGuard, PlannedAction, PlannedFile, SyncPlan, community_plugins_file, plugins_dir,
+};
+use report::{FileOutcome, FileReport, SyncReport};
+use serde_json::Value;
+use snapshot::SnapshotStore;
+use state::{FileStatus, StateStore, VaultState};
 use std::collections::HashSet;
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{self, BufReader};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
 use std::sync::mpsc::{self, channel};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
 use structs::{Action, VAULTS_FILE, Vaults};
-use tracing::{error, info};
+use tracing::{debug, error, info, instrument, warn};
+use trash::{Retention, Trash, TrashBatch};
+
+static SIGNATURES: LazyLock<SignatureCache> = LazyLock::new(SignatureCache::default);
+static TREES: LazyLock<TreeCache> = LazyLock::new(TreeCache::default);
+static STATE: LazyLock<StateStore> = LazyLock::new(StateStore::default);
+static CONFLICTS: LazyLock<ConflictStore> = LazyLock::new(ConflictStore::default);
+static OBJECTS: LazyLock<ObjectStore> = LazyLock::new(ObjectStore::default);
 
 pub fn watch_vault_list(tx: mpsc::Sender<Event>) -> Result<()> {
     let (watcher_tx, watcher_rx) = channel();
@@ -50,10 +82,10 @@ pub fn watch_vault_plugins(
     for event in watcher_rx {
         match event {
             Ok(event) => {
-                if let EventKind::Modify(ModifyKind::Data(_)) = event.kind {
-                    if not_syncing.load(std::sync::atomic::Ordering::Relaxed) {
-                        tx.send(Action::VaultPluginChanged(vault_path.clone()))?;
-                    }
+                if let EventKind::Modify(ModifyKind::Data(_)) = event.kind
+                    && not_syncing.load(std::sync::atomic::Ordering::Relaxed)
+                {
+                    tx.send(Action::VaultPluginChanged(vault_path.clone()))?;
                 }
             }
             Err(e) => {
@@ -66,7 +98,7 @@ pub fn watch_vault_plugins(
 
 pub async fn setup_vault_listeners(
     tx: tokio::sync::broadcast::Sender<Action>,
-    rx: &mut tokio::sync::broadcast::Receiver<Action>,
+    _rx: &mut tokio::sync::broadcast::Receiver<Action>,
     free: Arc<AtomicBool>,
 ) -> Result<()> {
     let (watcher_tx, watcher_rx) = channel();
@@ -123,7 +155,6 @@ pub async fn setup_vault_listeners(
     info!("Init watcher logging");
 
     let tx3 = tx.clone();
-    let mut rx3 = tx.subscribe();
     let watcher_thread: tokio::task::JoinHandle<color_eyre::eyre::Result<()>> =
         tokio::spawn(async move {
             for event in watcher_rx {
@@ -161,84 +192,731 @@ pub async fn setup_vault_listeners(
     Ok(())
 }
 
-fn read_file(path: &Path) -> Result<Vec<u8>> {
-    let mut buf = Vec::new();
-    fs::File::open(path)?.read_to_end(&mut buf)?;
-    Ok(buf)
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    let mut src = fs::File::open(from)?;
+    write_atomic(to, |out| {
+        io::copy(&mut src, out)?;
+        Ok(())
+    })
 }
 
-fn write_file(path: &Path, data: &[u8]) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?; // create dirs if missing
-    }
-    fs::File::create(path)?.write_all(data)?;
-    Ok(())
+/// Makes the plugins of the vault at `to` match those of the vault at `from`.
+/// Errors syncing a single file are recorded in the report and do not stop
+/// the other files from syncing. Fails with a [`GuardViolation`] without
+/// changing anything if the sync would delete more than `guard` allows.
+#[instrument(skip_all, fields(from = ?from, to = ?to))]
+pub async fn sync_vault(from: PathBuf, to: PathBuf, guard: &DeletionGuard) -> Result<SyncReport> {
+    let plan = plan_vault(&from, &to)?;
+    guard.check(&plan)?;
+    execute_plan(&plan)
 }
 
-pub async fn sync_vault(from: PathBuf, to: PathBuf) -> Result<()> {
-    let plugins_from = from.join(".obsidian").join("plugins");
-    let plugins_to = to.join(".obsidian").join("plugins");
-    dbg!(&plugins_from, &plugins_to);
-    let walk_result = Walk::new(&plugins_from);
-    dbg!("Finished Walk");
-
-    let from_community_plugins = from.join(".obsidian").join("community-plugins.json");
-    let to_community_plugins = to.join(".obsidian").join("community-plugins.json");
+/// Decides what syncing `from` to `to` would do without changing either
+/// vault. Updates come with the size of the delta they would apply, and
+/// files changed in both vaults since their last sync are in conflict.
+#[instrument(skip_all, fields(from = ?from, to = ?to))]
+pub fn plan_vault(from: &Path, to: &Path) -> Result<SyncPlan> {
+    let community_from = community_plugins_file(from);
+    let community_to = community_plugins_file(to);
+    let community_plugins = if community_from.exists() {
+        let src = hash_file(&community_from)?;
+        let dst = community_to
+            .exists()
+            .then(|| hash_file(&community_to))
+            .transpose()?;
+        PlannedAction::between(Some(src), dst)
+            .map(|action| Ok::<_, io::Error>((action, fs::metadata(&community_from)?.len())))
+            .transpose()?
+    } else {
+        warn!(file = ?community_from, "Source vault has no community plugins list");
+        None
+    };
 
-    sync_file(from_community_plugins, to_community_plugins)?;
+    let tree_from = TREES.tree_for(&plugins_dir(from))?;
+    let tree_to = TREES.tree_for(&plugins_dir(to))?;
+    if tree_from.root_hash() == tree_to.root_hash() {
+        info!("Plugins already in sync");
+    }
+    let mut plan = SyncPlan::from_trees(from, to, &tree_from, &tree_to, community_plugins);
+    mark_conflicts(&mut plan, &STATE.load(to)?)?;
+    for file in plan.all_files_mut() {
+        if let PlannedAction::Update { delta } = &mut file.action {
+            match compute_delta(&file.from, &file.to) {
+                Ok(patch) => {
+                    *delta = Some(patch.stats());
+                    file.patch = Some(Arc::new(patch));
+                }
+                Err(e) => warn!(file = ?file.to, "Could not compute delta: {}", e),
+            }
+        }
+    }
+    debug!(plan = %plan, "Planned sync");
+    Ok(plan)
+}
 
-    for entry in walk_result {
-        let entry = entry?;
-        dbg!(&entry);
-        if entry.file_type().unwrap().is_dir() {
+/// Compares every file `plan` would overwrite or delete with its state after
+/// the last sync. A destination file edited since then is never overwritten:
+/// if the source still has the synced version the destination is just newer
+/// and is skipped, otherwise both vaults changed it. Plugin settings are then
+/// merged if their synced version was kept; other files are in conflict.
+/// Once a vault has been synced, a file in it that never was is treated as
+/// edited since a sync of which nothing is known, so it is only overwritten
+/// on a vault's first sync, and then through the trash.
+fn mark_conflicts(plan: &mut SyncPlan, state: &VaultState) -> Result<()> {
+    let to = plan.to.clone();
+    for file in plan.all_files_mut() {
+        if !matches!(
+            file.action,
+            PlannedAction::Update { .. } | PlannedAction::Delete
+        ) {
             continue;
         }
-        let path = entry.path();
-        dbg!(&path);
-        let dst = plugins_to.join(path.strip_prefix(&plugins_from)?);
-        let src_bytes = read_file(path)?;
-        if dst.exists() {
-            let dst_bytes = read_file(&dst)?;
-            let del = Delta::new();
-            let delta = del.generate_delta(&dst_bytes, &src_bytes);
-            delta.apply(&src_bytes, dst.clone()).unwrap();
+        let rel = file.to.strip_prefix(&to)?;
+        let ours = hash_file(&file.to)?;
+        let base = match state.get(rel) {
+            Some(_) if state.status(rel, &ours) != FileStatus::Changed => continue,
+            Some(synced) => Some(*blake3::Hash::from_hex(&synced.hash)?.as_bytes()),
+            None if state.files.is_empty() => {
+                warn!(file = ?file.to, "Replacing a file in a vault that was never synced");
+                continue;
+            }
+            None => None,
+        };
+        let theirs = file
+            .from
+            .exists()
+            .then(|| hash_file(&file.from))
+            .transpose()?;
+        file.action = if theirs == base {
+            debug!(file = ?file.to, "Destination changed since the last sync, keeping it");
+            PlannedAction::Keep
+        } else if let Some(theirs) = theirs
+            && let Some(base) = base
+            && is_settings(rel)
+            && OBJECTS.contains(&base)
+        {
+            debug!(file = ?file.to, "Settings changed in both vaults, merging them");
+            PlannedAction::Merge { base, ours, theirs }
         } else {
-            write_file(&dst, &src_bytes)?;
+            warn!(file = ?file.to, "File changed in both vaults since the last sync");
+            PlannedAction::Conflict { base, ours, theirs }
+        };
+    }
+    Ok(())
+}
+
+fn compute_delta(from: &Path, to: &Path) -> Result<Delta> {
+    let sigs = SIGNATURES.signature_for(to)?;
+    Delta::from_reader(&sigs, fs::File::open(from)?)
+}
+
+/// Carries out a plan made by [`plan_vault`]. Files are checked again as they
+/// are synced, so a file that changed since planning is still synced
+/// correctly. Fails without changing anything if the vault's trash cannot
+/// take the files the sync would delete or overwrite.
+#[instrument(skip_all, fields(from = ?plan.from, to = ?plan.to))]
+pub fn execute_plan(plan: &SyncPlan) -> Result<SyncReport> {
+    let started = Instant::now();
+    let mut report = SyncReport::new(plan.from.clone(), plan.to.clone());
+    let trash = Trash::for_vault(&plan.to);
+    let mut batch = trash.begin()?;
+    for file in plan.all_files() {
+        report.record(match file.action {
+            PlannedAction::Skip => FileReport {
+                bytes: file.bytes,
+                ..FileReport::new(file.to.clone(), FileOutcome::Unchanged)
+            },
+            PlannedAction::Keep => FileReport {
+                bytes: file.bytes,
+                ..FileReport::new(file.to.clone(), FileOutcome::Kept)
+            },
+            PlannedAction::Create => sync_file_reporting(file.from.clone(), file.to.clone(), None),
+            PlannedAction::Update { .. } => match batch.keep(&file.to) {
+                Ok(()) => {
+                    sync_file_reporting(file.from.clone(), file.to.clone(), file.patch.as_deref())
+                }
+                Err(e) => {
+                    error!(file = ?file.to, "Could not move file to the trash: {:#}", e);
+                    FileReport::failed(file.to.clone(), format!("{e:#}"))
+                }
+            },
+            // --- Delete files that no longer exist in "from" ---
+            PlannedAction::Delete => {
+                let deleted = delete_file(file.to.clone(), &mut batch);
+                if deleted.outcome == FileOutcome::Deleted {
+                    remove_empty_parents(&file.to, &plugins_dir(&plan.to));
+                }
+                deleted
+            }
+            PlannedAction::Conflict { base, ours, theirs } => {
+                keep_conflict(plan, file, base.as_ref(), &ours, theirs.is_some())
+            }
+            PlannedAction::Merge { base, ours, .. } => {
+                merge_settings(plan, file, &base, &ours, &mut batch)
+            }
+        });
+    }
+    let synced = report
+        .files
+        .iter()
+        .filter(|f| !matches!(f.outcome, FileOutcome::Failed | FileOutcome::Conflict))
+        .filter_map(|f| f.path.strip_prefix(&plan.to).ok())
+        .collect::<Vec<_>>();
+    for vault in [&plan.from, &plan.to] {
+        if let Err(e) = CONFLICTS.resolve(vault, synced.iter().copied()) {
+            warn!(vault = ?vault, "Could not update conflicts: {}", e);
+        }
+    }
+    report.duration = started.elapsed();
+    if !batch.is_empty() {
+        info!(sync_id = batch.sync_id(), "Moved old files to the trash");
+        report.trash = Some(batch.sync_id().to_owned());
+        if let Err(e) = trash.prune(&Retention::default()) {
+            warn!("Could not prune the trash: {}", e);
+        }
+    }
+    info!(report = %report, "Synced vault");
+    if let Err(e) = report.save() {
+        warn!("Could not save sync report: {}", e);
+    }
+    if let Err(e) = STATE.record(&report) {
+        warn!("Could not record sync state: {}", e);
+    }
+    Ok(report)
+}
+
+/// Removes stored objects that nothing refers to anymore: not a snapshot in
+/// `snapshots`, not the synced settings of any vault and not either side of
+/// an open conflict. Returns how many objects were removed.
+pub fn collect_garbage(snapshots: &SnapshotStore) -> Result<usize> {
+    let mut live = HashSet::new();
+    let mut mark = |hex: &str| -> Result<()> {
+        live.insert(*blake3::Hash::from_hex(hex)?.as_bytes());
+        Ok(())
+    };
+    for snapshot in snapshots.list()? {
+        for file in snapshot.files.values() {
+            mark(&file.hash)?;
+        }
+    }
+    for state in STATE.list()? {
+        for file in state.files.values() {
+            mark(&file.hash)?;
         }
     }
-    // --- Delete files that no longer exist in "from" ---
-    for entry in Walk::new(&plugins_to).filter_map(Result::ok) {
-        if entry.file_type().expect("NO file type found").is_file() {
-            let rel_path = entry.path().strip_prefix(&plugins_to)?;
-            let src_path = plugins_from.join(rel_path);
-            if !src_path.exists() {
-                dbg!("Deleting", &entry.path());
-                fs::remove_file(entry.path())?;
+    for conflict in CONFLICTS.list()? {
+        mark(&conflict.ours)?;
+        for hash in [&conflict.base, &conflict.theirs].into_iter().flatten() {
+            mark(hash)?;
+        }
+    }
+    let removed = OBJECTS.retain(&live)?;
+    info!(removed, kept = live.len(), "Collected unused objects");
+    Ok(removed)
+}
+
+/// Leaves a conflicting destination file alone and records the conflict,
+/// keeping the source's copy in the object store.
+fn keep_conflict(
+    plan: &SyncPlan,
+    file: &PlannedFile,
+    base: Option<&[u8; 32]>,
+    ours: &[u8; 32],
+    has_theirs: bool,
+) -> FileReport {
+    let result = file
+        .to
+        .strip_prefix(&plan.to)
+        .map_err(Into::into)
+        .and_then(|rel| {
+            let conflict = Conflict::new(&plan.to, rel, &plan.from, base, ours);
+            CONFLICTS.record(conflict, has_theirs.then_some(file.from.as_path()))
+        });
+    match result {
+        Ok(_) => FileReport {
+            bytes: file.bytes,
+            ..FileReport::new(file.to.clone(), FileOutcome::Conflict)
+        },
+        Err(e) => {
+            error!(file = ?file.to, "Could not record conflict: {:#}", e);
+            FileReport::failed(file.to.clone(), format!("{e:#}"))
+        }
+    }
+}
+
+/// Merges plugin settings changed in both vaults into the destination. Keys
+/// changed differently in both keep the destination's value and are recorded
+/// as a conflict. Settings that cannot be merged, such as invalid JSON, are
+/// left alone as a whole-file conflict.
+fn merge_settings(
+    plan: &SyncPlan,
+    file: &PlannedFile,
+    base: &[u8; 32],
+    ours: &[u8; 32],
+    batch: &mut TrashBatch,
+) -> FileReport {
+    let started = Instant::now();
+    let mut report = try_merge_settings(plan, file, base, batch).unwrap_or_else(|e| {
+        warn!(file = ?file.to, "Could not merge settings: {:#}", e);
+        keep_conflict(plan, file, Some(base), ours, true)
+    });
+    report.duration = started.elapsed();
+    report
+}
+
+fn try_merge_settings(
+    plan: &SyncPlan,
+    file: &PlannedFile,
+    base: &[u8; 32],
+    batch: &mut TrashBatch,
+) -> Result<FileReport> {
+    let rel = file.to.strip_prefix(&plan.to)?;
+    let base_value: Value = serde_json::from_slice(&OBJECTS.read(base)?)?;
+    let ours: Value = serde_json::from_slice(&fs::read(&file.to)?)?;
+    let theirs: Value = serde_json::from_slice(&fs::read(&file.from)?)?;
+    let result = merge::merge(&base_value, &ours, &theirs);
+    let contents = serde_json::to_vec_pretty(&result.merged)?;
+    let bytes = contents.len() as u64;
+    let mut bytes_written = 0;
+    if result.merged != ours {
+        batch.keep(&file.to)?;
+        write_atomic(&file.to, |out| {
+            out.write_all(&contents)?;
+            Ok(())
+        })?;
+        bytes_written = bytes;
+    }
+    if result.is_clean() {
+        info!(file = ?file.to, "Merged settings changed in both vaults");
+        return Ok(FileReport {
+            bytes,
+            bytes_written,
+            ..FileReport::new(file.to.clone(), FileOutcome::Merged)
+        });
+    }
+    let keys: Vec<String> = result.conflicts.into_iter().map(|c| c.pointer).collect();
+    warn!(file = ?file.to, keys = ?keys, "Settings keys changed in both vaults");
+    // what is on disk now, which is not the merged JSON if it was left alone
+    let ours = hash_file(&file.to)?;
+    let conflict = Conflict {
+        keys,
+        ..Conflict::new(&plan.to, rel, &plan.from, Some(base), &ours)
+    };
+    CONFLICTS.record(conflict, Some(&file.from))?;
+    Ok(FileReport {
+        bytes,
+        bytes_written,
+        ..FileReport::new(file.to.clone(), FileOutcome::Conflict)
+    })
+}
+
+/// Runs [`sync_file`], turning an error into a failed entry of the report.
+fn sync_file_reporting(from: PathBuf, to: PathBuf, planned: Option<&Delta>) -> FileReport {
+    let started = Instant::now();
+    let mut file = sync_file_with(from, to.clone(), planned).unwrap_or_else(|e| {
+        error!(file = ?to, "Could not sync file: {:#}", e);
+        FileReport::failed(to, format!("{e:#}"))
+    });
+    file.duration = started.elapsed();
+    file
+}
+
+/// Moves `to` into the sync's trash batch rather than removing it outright.
+#[instrument(skip_all, fields(file = ?to))]
+fn delete_file(to: PathBuf, batch: &mut TrashBatch) -> FileReport {
+    let started = Instant::now();
+    let result = fs::metadata(&to)
+        .map_err(color_eyre::Report::from)
+        .and_then(|metadata| {
+            batch.take(&to)?;
+            Ok(metadata.len())
+        });
+    let mut file = match result {
+        Ok(bytes) => {
+            debug!("Deleted file");
+            FileReport {
+                bytes,
+                ..FileReport::new(to, FileOutcome::Deleted)
             }
         }
+        Err(e) => {
+            error!("Could not delete file: {:#}", e);
+            FileReport::failed(to, format!("{e:#}"))
+        }
+    };
+    file.duration = started.elapsed();
+    file
+}
+
+/// Removes the directories above `path` that deleting it left empty, up to
+/// but not including `root`, so a file can take the place of a directory.
+fn remove_empty_parents(path: &Path, root: &Path) {
+    for dir in path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(root) && *dir != root)
+    {
+        if fs::remove_dir(dir).is_err() {
+            break;
+        }
     }
-    Ok(())
 }
 
-pub fn sync_file(from: PathBuf, to: PathBuf) -> Result<()> {
-    let src_bytes = read_file(&from)?;
-    if to.exists() {
-        let dst_bytes = read_file(&to)?;
-        let del = Delta::new();
-        let delta = del.generate_delta(&dst_bytes, &src_bytes);
-        delta.apply(&src_bytes, to.clone()).unwrap();
-    } else {
-        write_file(&to, &src_bytes)?;
+/// Makes `to` a copy of `from`. Files that are already identical are left
+/// alone, so their mtime does not change and other vaults see no events;
+/// otherwise the file is patched with a delta, or copied if it is new.
+pub fn sync_file(from: PathBuf, to: PathBuf) -> Result<FileReport> {
+    sync_file_with(from, to, None)
+}
+
+/// Like [`sync_file`], but patches with `planned`, the delta computed when
+/// the sync was planned, if neither file has changed since.
+#[instrument(skip_all, fields(file = ?to))]
+fn sync_file_with(from: PathBuf, to: PathBuf, planned: Option<&Delta>) -> Result<FileReport> {
+    let bytes = fs::metadata(&from)?.len();
+    if !to.exists() {
+        copy_file(&from, &to)?;
+        debug!(bytes, "Created file");
+        return Ok(FileReport {
+            bytes,
+            bytes_written: bytes,
+            ..FileReport::new(to, FileOutcome::Created)
+        });
     }
-    Ok(())
+    if same_contents(&from, &to)? {
+        debug!("Already up to date");
+        return Ok(FileReport {
+            bytes,
+            ..FileReport::new(to, FileOutcome::Unchanged)
+        });
+    }
+    let sigs = SIGNATURES.signature_for(&to)?;
+    let generated;
+    let delta = match planned {
+        Some(delta)
+            if delta.check_signature(&sigs).is_ok()
+                && delta.target_digest() == hash_file(&from)? =>
+        {
+            delta
+        }
+        _ => {
+            generated = Delta::from_reader(&sigs, fs::File::open(&from)?)?;
+            &generated
+        }
+    };
+    let stats = delta.stats();
+    info!(
+        block_size = sigs.block_size(),
+        stats = %stats,
+        "Applying delta"
+    );
+    let mut bytes_written = stats.bytes_inserted;
+    if let Err(e) = delta.apply_from(BufReader::new(fs::File::open(&to)?), to.clone()) {
+        if e.downcast_ref::<DeltaError>().is_none() {
+            return Err(e);
+        }
+        warn!("Delta verification failed, copying the whole file: {}", e);
+        copy_file(&from, &to)?;
+        bytes_written = bytes;
+    }
+    Ok(FileReport {
+        bytes,
+        bytes_written,
+        delta: Some(stats),
+        ..FileReport::new(to, FileOutcome::Patched)
+    })
+}
+
+/// Compares sizes first and only hashes both files when they match.
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    Ok(hash_file(a)? == hash_file(b)?)
+}
+
+/// Applies a delta received from another syncer to `to`. Plugins are code, so
+/// the delta is refused unless a trusted peer signed it, and it must have been
+/// generated against our current signature of `to`. Pass `key` when deltas
+/// are exchanged encrypted.
+pub fn receive_delta(
+    envelope: &[u8],
+    to: PathBuf,
+    peers: &TrustedPeers,
+    key: Option<&EncryptionKey>,
+) -> Result<()> {
+    let delta = match key {
+        Some(key) => Delta::decode_sealed(envelope, key, peers)?,
+        None => Delta::decode_signed(envelope, peers)?,
+    };
+    let sigs = SIGNATURES.signature_for(&to)?;
+    delta.check_signature(&sigs)?;
+    info!(file = ?to, stats = %delta.stats(), "Applying received delta");
+    delta.apply_from(BufReader::new(fs::File::open(&to)?), to)
 }
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::plan::GuardViolation;
+    #[tokio::test]
+    async fn test_identical_files_are_not_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        for vault in [&from, &to] {
+            let plugin = vault.join(".obsidian").join("plugins").join("calendar");
+            fs::create_dir_all(&plugin).unwrap();
+            fs::write(vault.join(".obsidian").join("community-plugins.json"), "[]").unwrap();
+            fs::write(plugin.join("main.js"), "console.log(1)").unwrap();
+        }
+        let plugins_from = from.join(".obsidian").join("plugins");
+        let plugins_to = to.join(".obsidian").join("plugins");
+        fs::write(plugins_from.join("calendar").join("data.json"), "{}").unwrap();
+        fs::write(plugins_to.join("stale.js"), "gone").unwrap();
+
+        let main_js = plugins_to.join("calendar").join("main.js");
+        let mtime = fs::metadata(&main_js).unwrap().modified().unwrap();
+        let report = sync_vault(from.clone(), to.clone(), &DeletionGuard::default())
+            .await
+            .unwrap();
+        assert_eq!(fs::metadata(&main_js).unwrap().modified().unwrap(), mtime);
+        assert_eq!(report.skipped().count(), 2);
+        assert_eq!(report.created().count(), 1);
+        assert_eq!(report.deleted().count(), 1);
+        assert_eq!(report.errors().count(), 0);
+        assert_eq!(report.bytes_written(), 2);
+        assert!(!plugins_to.join("stale.js").exists());
+
+        let report = sync_vault(from, to, &DeletionGuard::default())
+            .await
+            .unwrap();
+        assert!(!report.changed_anything());
+
+        fs::write(
+            plugins_from.join("calendar").join("main.js"),
+            "console.log(2)",
+        )
+        .unwrap();
+        assert_eq!(
+            sync_file(
+                plugins_from.join("calendar").join("main.js"),
+                main_js.clone()
+            )
+            .unwrap()
+            .outcome,
+            FileOutcome::Patched
+        );
+        assert_eq!(fs::read(&main_js).unwrap(), b"console.log(2)");
+    }
+
+    #[tokio::test]
+    async fn test_edits_in_both_vaults_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        let plugin_from = plugins_dir(&from).join("calendar");
+        let plugin_to = plugins_dir(&to).join("calendar");
+        fs::create_dir_all(&plugin_from).unwrap();
+        fs::write(plugin_from.join("data.json"), r#"{"week":1}"#).unwrap();
+        fs::write(plugin_from.join("main.js"), "v1").unwrap();
+        let guard = DeletionGuard::default();
+        sync_vault(from.clone(), to.clone(), &guard).await.unwrap();
+
+        // only the destination changed: it is newer, not stale, and stays so
+        // however often the source syncs
+        fs::write(plugin_to.join("main.js"), "v1 patched").unwrap();
+        let plan = plan_vault(&from, &to).unwrap();
+        assert!(plan.is_noop());
+        for _ in 0..2 {
+            let report = sync_vault(from.clone(), to.clone(), &guard).await.unwrap();
+            assert_eq!(report.count(FileOutcome::Kept), 1);
+            assert_eq!(fs::read(plugin_to.join("main.js")).unwrap(), b"v1 patched");
+        }
+
+        // both changed: neither side wins
+        fs::write(plugin_from.join("data.json"), r#"{"week":2}"#).unwrap();
+        fs::write(plugin_to.join("data.json"), r#"{"week":3}"#).unwrap();
+        let report = sync_vault(from.clone(), to.clone(), &guard).await.unwrap();
+        assert_eq!(report.conflicts().count(), 1);
+        assert_eq!(
+            fs::read(plugin_to.join("data.json")).unwrap(),
+            br#"{"week":3}"#
+        );
+        let open = CONFLICTS.load(&to).unwrap();
+        let conflict = &open[Path::new(".obsidian/plugins/calendar/data.json")];
+        assert_eq!(conflict.origin, from);
+        assert!(conflict.theirs.is_some());
+        // the settings were left as they were, not rewritten as merged JSON
+        assert_eq!(
+            conflict.ours,
+            blake3::hash(br#"{"week":3}"#).to_hex().as_str()
+        );
+
+        // once the vaults agree again the conflict is gone
+        fs::write(plugin_to.join("data.json"), r#"{"week":2}"#).unwrap();
+        sync_vault(from, to.clone(), &guard).await.unwrap();
+        assert!(CONFLICTS.load(&to).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_files_never_synced_are_not_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        let plugin_from = plugins_dir(&from).join("calendar");
+        let plugin_to = plugins_dir(&to).join("calendar");
+        fs::create_dir_all(&plugin_from).unwrap();
+        fs::write(plugin_from.join("main.js"), "v1").unwrap();
+        let guard = DeletionGuard::default();
+        sync_vault(from.clone(), to.clone(), &guard).await.unwrap();
+
+        // added to the destination only: kept
+        fs::write(plugin_to.join("local.js"), "mine").unwrap();
+        // added to both with different contents: neither wins
+        fs::write(plugin_from.join("new.js"), "theirs").unwrap();
+        fs::write(plugin_to.join("new.js"), "ours").unwrap();
+        let report = sync_vault(from.clone(), to.clone(), &guard).await.unwrap();
+        assert_eq!(report.count(FileOutcome::Deleted), 0);
+        assert_eq!(report.conflicts().count(), 1);
+        assert_eq!(fs::read(plugin_to.join("local.js")).unwrap(), b"mine");
+        assert_eq!(fs::read(plugin_to.join("new.js")).unwrap(), b"ours");
+        let open = CONFLICTS.load(&to).unwrap();
+        let conflict = &open[Path::new(".obsidian/plugins/calendar/new.js")];
+        assert_eq!(conflict.base, None);
+        assert!(conflict.theirs.is_some());
+
+        // syncing again does not mistake the kept file for a synced one
+        let report = sync_vault(from, to, &guard).await.unwrap();
+        assert_eq!(report.count(FileOutcome::Deleted), 0);
+        assert_eq!(fs::read(plugin_to.join("local.js")).unwrap(), b"mine");
+    }
+
+    #[tokio::test]
+    async fn test_settings_changed_in_both_vaults_are_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        let plugin_from = plugins_dir(&from).join("calendar");
+        let plugin_to = plugins_dir(&to).join("calendar");
+        fs::create_dir_all(&plugin_from).unwrap();
+        fs::write(
+            plugin_from.join("data.json"),
+            r#"{"theme":"light","week":1}"#,
+        )
+        .unwrap();
+        let guard = DeletionGuard::default();
+        sync_vault(from.clone(), to.clone(), &guard).await.unwrap();
+
+        fs::write(
+            plugin_from.join("data.json"),
+            r#"{"theme":"light","week":2}"#,
+        )
+        .unwrap();
+        fs::write(plugin_to.join("data.json"), r#"{"theme":"dark","week":1}"#).unwrap();
+        let plan = plan_vault(&from, &to).unwrap();
+        assert_eq!(plan.merges().count(), 1);
+        let report = execute_plan(&plan).unwrap();
+        assert_eq!(report.count(FileOutcome::Merged), 1);
+        let merged: Value =
+            serde_json::from_slice(&fs::read(plugin_to.join("data.json")).unwrap()).unwrap();
+        assert_eq!(merged, serde_json::json!({"theme": "dark", "week": 2}));
+
+        // the merge reaches the other vault as a plain update
+        let report = sync_vault(to, from, &guard).await.unwrap();
+        assert_eq!(report.updated().count(), 1);
+        assert_eq!(
+            fs::read(plugin_from.join("data.json")).unwrap(),
+            fs::read(plugin_to.join("data.json")).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_planned_delta_is_used_while_current() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        let main_from = plugins_dir(&from).join("calendar").join("main.js");
+        let main_to = plugins_dir(&to).join("calendar").join("main.js");
+        for main_js in [&main_from, &main_to] {
+            fs::create_dir_all(main_js.parent().unwrap()).unwrap();
+        }
+        fs::write(&main_to, "console.log(1)".repeat(100)).unwrap();
+        fs::write(&main_from, "console.log(2)".repeat(100)).unwrap();
+
+        let plan = plan_vault(&from, &to).unwrap();
+        let update = plan.updates().next().unwrap();
+        let patch = update.patch.as_deref().unwrap();
+        assert_eq!(
+            update.action,
+            PlannedAction::Update {
+                delta: Some(patch.stats())
+            }
+        );
+        let report = execute_plan(&plan).unwrap();
+        assert_eq!(report.updated().count(), 1);
+        assert_eq!(fs::read(&main_to).unwrap(), fs::read(&main_from).unwrap());
+
+        // a source edited after planning is not synced from the stale delta
+        fs::write(&main_from, "console.log(3)".repeat(100)).unwrap();
+        let plan = plan_vault(&from, &to).unwrap();
+        fs::write(&main_from, "console.log(4)".repeat(100)).unwrap();
+        execute_plan(&plan).unwrap();
+        assert_eq!(fs::read(&main_to).unwrap(), fs::read(&main_from).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_directory_and_file_swap_places() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        let lib_from = plugins_dir(&from).join("calendar").join("lib");
+        let lib_to = plugins_dir(&to).join("calendar").join("lib");
+        fs::create_dir_all(lib_from.parent().unwrap()).unwrap();
+        fs::write(&lib_from, "bundled").unwrap();
+        fs::create_dir_all(&lib_to).unwrap();
+        fs::write(lib_to.join("a.js"), "a").unwrap();
+        fs::write(lib_to.join("b.js"), "b").unwrap();
+        let guard = DeletionGuard {
+            max_percent: 100.0,
+            ..DeletionGuard::default()
+        };
+
+        let report = sync_vault(from.clone(), to.clone(), &guard).await.unwrap();
+        assert_eq!(report.errors().count(), 0);
+        assert_eq!(report.deleted().count(), 2);
+        assert_eq!(fs::read(&lib_to).unwrap(), b"bundled");
+
+        // and back again
+        let report = sync_vault(from.clone(), to.clone(), &guard).await.unwrap();
+        assert!(!report.changed_anything());
+        fs::remove_file(&lib_from).unwrap();
+        fs::create_dir_all(&lib_from).unwrap();
+        fs::write(lib_from.join("a.js"), "a").unwrap();
+        let report = sync_vault(from, to, &guard).await.unwrap();
+        assert_eq!(report.errors().count(), 0);
+        assert_eq!(fs::read(lib_to.join("a.js")).unwrap(), b"a");
+    }
+
+    #[tokio::test]
+    async fn test_empty_source_deletes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        fs::create_dir_all(plugins_dir(&from)).unwrap();
+        let plugin = plugins_dir(&to).join("calendar");
+        fs::create_dir_all(&plugin).unwrap();
+        fs::write(plugin.join("main.js"), "console.log(1)").unwrap();
+
+        let err = sync_vault(from, to, &DeletionGuard::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GuardViolation>(),
+            Some(GuardViolation::EmptySource { deletes: 1 })
+        ));
+        assert!(plugin.join("main.js").exists());
+    }
+
     #[tokio::test]
     async fn test_transfer() {
         let from = PathBuf::from("/Users/jayansunil/Dev/rust/obsidian_syncer/test/from");
         let to = PathBuf::from("/Users/jayansunil/Dev/rust/obsidian_syncer/test/to");
-        sync_vault(from, to).await.unwrap();
+        sync_vault(from, to, &DeletionGuard::default())
+            .await
+            .unwrap();
     }
 }
@@ -13,9 +13,33 @@ pub static LOG_ENV: LazyLock<String> =
     LazyLock::new(|| format!("{}_LOG_LEVEL", PROJECT_NAME.clone()));
 pub static LOG_FILE: LazyLock<String> = LazyLock::new(|| format!("{}.log", env!("CARGO_PKG_NAME")));
 pub static DATA_FOLDER: LazyLock<Option<PathBuf>> = LazyLock::new(|| {
-    env::var(format!("{}_DATA", PROJECT_NAME.clone()))
-        .ok()
-        .map(PathBuf::from)
+    #[cfg(test)]
+    {
+        // tests keep their caches, trash, state and reports out of the real
+        // data dir
+        Some(TEST_DATA.path().to_path_buf())
+    }
+    #[cfg(not(test))]
+    {
+        env::var(format!("{}_DATA", PROJECT_NAME.clone()))
+            .ok()
+            .map(PathBuf::from)
+    }
+});
+
+/// Scratch data dir of a test run. Statics are never dropped, so it is
+/// removed when the process exits instead.
+#[cfg(test)]
+static TEST_DATA: LazyLock<tempfile::TempDir> = LazyLock::new(|| {
+    extern "C" fn remove_test_data() {
+        let _ = std::fs::remove_dir_all(TEST_DATA.path());
+    }
+    // SAFETY: registers a plain function that takes no arguments
+    unsafe { libc::atexit(remove_test_data) };
+    tempfile::Builder::new()
+        .prefix(concat!(env!("CARGO_PKG_NAME"), "-test-"))
+        .tempdir()
+        .expect("could not create a scratch data dir for tests")
 });
 
 pub fn init() -> Result<()> {
@@ -44,14 +68,13 @@ pub fn init() -> Result<()> {
     Ok(())
 }
 pub fn get_data_dir() -> PathBuf {
-    let directory = if let Some(s) = DATA_FOLDER.clone() {
+    if let Some(s) = DATA_FOLDER.clone() {
         s
     } else if let Some(proj_dirs) = project_directory() {
         proj_dirs.data_local_dir().to_path_buf()
     } else {
         PathBuf::from(".").join(".data")
-    };
-    directory
+    }
 }
 
 fn project_directory() -> Option<ProjectDirs> {
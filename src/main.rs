@@ -1,21 +1,41 @@
+mod cli;
 mod errors;
-mod logging;
 use std::fs::read_dir;
+use std::io::{IsTerminal, Write};
+use std::path::absolute;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
+use std::time::{Duration, SystemTime};
 
+use clap::Parser;
+use cli::{Cli, Command, List};
 use color_eyre::eyre::Result;
 use itertools::Itertools;
+use obsidian_syncer::conflict::ConflictStore;
+use obsidian_syncer::logging;
+use obsidian_syncer::plan::{GuardViolation, SyncPlan};
+use obsidian_syncer::snapshot::SnapshotStore;
 use obsidian_syncer::structs::*;
-use obsidian_syncer::sync_vault;
+use obsidian_syncer::trash::{Retention, Trash};
+use obsidian_syncer::{collect_garbage, execute_plan, plan_vault};
 use tokio::sync::broadcast;
 use tracing::debug;
+use tracing::error;
 use tracing::info;
+use tracing::warn;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
     logging::init()?;
     errors::init()?;
+    if let Some(command) = &cli.command {
+        return run_command(command);
+    }
+    if cli.dry_run {
+        info!("Dry run: vaults will not be changed");
+    }
+    let guard = cli.deletion_guard();
     let (tx, mut rx1) = broadcast::channel(100);
     let rx2 = tx.subscribe();
     info!("Test logging");
@@ -37,7 +57,7 @@ async fn main() -> Result<()> {
         .await
         .unwrap();
     });
-    let tx_move = tx.clone();
+    let tx_syncer = tx.clone();
     let _thread_syncer: tokio::task::JoinHandle<std::result::Result<(), color_eyre::eyre::Error>> =
         tokio::spawn(async move {
             let is_free1 = Arc::clone(&is_free);
@@ -61,6 +81,10 @@ async fn main() -> Result<()> {
                             .collect_vec();
                         debug!("TEST SYNCER");
 
+                        let tx_conflicts = tx_syncer.clone();
+                        let snapshots = SnapshotStore::default();
+                        let snapshot_id = snapshots.next_id();
+
                         let is_free2 = Arc::clone(&is_free1);
                         Arc::clone(&is_free2).store(false, std::sync::atomic::Ordering::SeqCst);
                         let _thread: tokio::task::JoinHandle<
@@ -83,10 +107,80 @@ async fn main() -> Result<()> {
                                 if entries.contains(&"no_sync".to_owned().into()) {
                                     continue;
                                 }
-                                sync_vault(vault_path.clone(), vault.path.clone()).await?;
+                                let plan = plan_vault(&vault_path, &vault.path)?;
+                                let allowed = guard.check(&plan);
+                                if cli.dry_run {
+                                    if let Err(violation) = allowed {
+                                        warn!(
+                                            vault = ?vault.path,
+                                            "Dry run, sync would need confirmation: {}",
+                                            violation
+                                        );
+                                    }
+                                    info!(plan = %plan, "Dry run, not syncing vault");
+                                    continue;
+                                }
+                                if let Err(violation) = allowed {
+                                    if cli.force {
+                                        warn!(
+                                            vault = ?vault.path,
+                                            "Going past deletion limits: {}",
+                                            violation
+                                        );
+                                    } else if !confirm_deletions(&plan, &violation).await? {
+                                        error!(
+                                            vault = ?vault.path,
+                                            "Not syncing vault, rerun with --force to override: {}",
+                                            violation
+                                        );
+                                        continue;
+                                    }
+                                }
+                                if !plan.is_noop()
+                                    && let Err(e) = snapshots.take(&vault.path, &snapshot_id)
+                                {
+                                    error!(
+                                        vault = ?vault.path,
+                                        "Not syncing vault, could not snapshot it: {:#}",
+                                        e
+                                    );
+                                    continue;
+                                }
+                                let report = match execute_plan(&plan) {
+                                    Ok(report) => report,
+                                    Err(e) => {
+                                        error!(
+                                            vault = ?vault.path,
+                                            "Not syncing vault: {:#}",
+                                            e
+                                        );
+                                        continue;
+                                    }
+                                };
+                                for file in report.conflicts() {
+                                    tx_conflicts.send(Action::Conflict {
+                                        vault: vault.path.clone(),
+                                        file: file.path.clone(),
+                                    })?;
+                                }
+                                if report.errors().next().is_some() {
+                                    warn!(
+                                        vault = ?vault.path,
+                                        failed = report.errors().count(),
+                                        "Some plugin files could not be synced"
+                                    );
+                                }
                             }
 
                             debug!("Finished Syncing Operation");
+                            if !cli.dry_run {
+                                if let Err(e) = snapshots.prune(&Retention::default()) {
+                                    warn!("Could not prune snapshots: {:#}", e);
+                                }
+                                if let Err(e) = collect_garbage(&snapshots) {
+                                    warn!("Could not remove unused objects: {:#}", e);
+                                }
+                            }
 
                             Ok(())
                         });
@@ -129,3 +223,119 @@ async fn main() -> Result<()> {
     _thread_vault_list.join().unwrap();
     Ok(())
 }
+
+/// Asks on the terminal whether to go ahead with a sync that breaks the
+/// deletion limits. Without a terminal to ask on, the answer is no.
+async fn confirm_deletions(plan: &SyncPlan, violation: &GuardViolation) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    let prompt = format!(
+        "{violation}\n{plan}\nDelete these files from {}? [y/N] ",
+        plan.to.display()
+    );
+    let answer = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        let mut stderr = std::io::stderr();
+        write!(stderr, "{prompt}")?;
+        stderr.flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        Ok(answer)
+    })
+    .await??;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Runs a one-off subcommand instead of watching the vaults.
+fn run_command(command: &Command) -> Result<()> {
+    match command {
+        Command::List(List::Snapshots) => {
+            let snapshots = SnapshotStore::default().list()?;
+            for (id, round) in &snapshots.iter().chunk_by(|s| &s.id) {
+                let round = round.collect_vec();
+                println!("{id}  {} ago", age(round[0].created));
+                for snapshot in round {
+                    println!(
+                        "    {}  {} files, {} bytes",
+                        snapshot.vault.display(),
+                        snapshot.files.len(),
+                        snapshot.bytes()
+                    );
+                }
+            }
+        }
+        Command::List(List::Conflicts) => {
+            for conflict in ConflictStore::default().list()? {
+                println!(
+                    "{}  {}  changed here and in {}, {} ago",
+                    conflict.vault.display(),
+                    conflict.path.display(),
+                    conflict.origin.display(),
+                    age(conflict.detected)
+                );
+                if !conflict.keys.is_empty() {
+                    println!("    clashing settings: {}", conflict.keys.join(", "));
+                }
+                if conflict.theirs.is_some() {
+                    println!(
+                        "    their copy: extract {} {} <file>",
+                        conflict.vault.display(),
+                        conflict.path.display()
+                    );
+                } else {
+                    println!("    deleted in {}", conflict.origin.display());
+                }
+            }
+        }
+        Command::List(List::Trash { vault }) => {
+            for manifest in Trash::for_vault(&absolute(vault)?).list()? {
+                println!(
+                    "{}  {} files, {} ago",
+                    manifest.sync_id,
+                    manifest.entries.len(),
+                    age(manifest.created)
+                );
+                for entry in &manifest.entries {
+                    println!("    {:?} {}", entry.reason, entry.path.display());
+                }
+            }
+        }
+        Command::Restore { vault, sync_id } => {
+            for path in Trash::for_vault(&absolute(vault)?).restore(sync_id)? {
+                println!("restored {}", path.display());
+            }
+        }
+        Command::Extract { vault, path, out } => {
+            ConflictStore::default().extract_theirs(&absolute(vault)?, path, out)?;
+            println!("wrote {}", out.display());
+        }
+        Command::Rollback {
+            snapshot, vault, ..
+        } => {
+            let store = SnapshotStore::default();
+            let vaults = match vault {
+                Some(vault) => vec![absolute(vault)?],
+                // --all
+                None => store
+                    .round(snapshot)?
+                    .into_iter()
+                    .map(|s| s.vault)
+                    .collect(),
+            };
+            for vault in vaults {
+                let report = store.rollback(&vault, snapshot)?;
+                println!("{report}");
+                if let Some(trash) = &report.trash {
+                    println!("undo with: restore {} {trash}", vault.display());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How long ago `time` was, to the second.
+fn age(time: SystemTime) -> String {
+    let age = time.elapsed().unwrap_or_default();
+    format!("{:?}", Duration::from_secs(age.as_secs()))
+}
@@ -0,0 +1,142 @@
+//! Three-way merge of plugin settings.
+//!
+//! Plugins keep their settings in `data.json`. When two vaults change it
+//! between syncs, [`merge`] compares both copies key by key with the version
+//! they were last synced at, so settings changed in different vaults both
+//! survive. Only a key that was changed differently in both is a conflict;
+//! it keeps our value.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Whether the file at `path` holds plugin settings that can be merged.
+pub fn is_settings(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == "data.json")
+}
+
+/// A key that was changed differently in both copies. Values are `None`
+/// where the key is missing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyConflict {
+    /// JSON pointer to the key, `""` for the whole document.
+    pub pointer: String,
+    pub base: Option<Value>,
+    pub ours: Option<Value>,
+    pub theirs: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonMerge {
+    pub merged: Value,
+    pub conflicts: Vec<KeyConflict>,
+}
+
+impl JsonMerge {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// Merges the changes `ours` and `theirs` made to `base`. Objects are merged
+/// key by key; any other value, arrays included, is changed as a whole.
+pub fn merge(base: &Value, ours: &Value, theirs: &Value) -> JsonMerge {
+    let mut conflicts = Vec::new();
+    let merged = merge_at(
+        String::new(),
+        Some(base),
+        Some(ours),
+        Some(theirs),
+        &mut conflicts,
+    )
+    .unwrap_or(Value::Null);
+    JsonMerge { merged, conflicts }
+}
+
+fn merge_at(
+    pointer: String,
+    base: Option<&Value>,
+    ours: Option<&Value>,
+    theirs: Option<&Value>,
+    conflicts: &mut Vec<KeyConflict>,
+) -> Option<Value> {
+    if ours == theirs || theirs == base {
+        return ours.cloned();
+    }
+    if ours == base {
+        return theirs.cloned();
+    }
+    if let (Some(Value::Object(ours)), Some(Value::Object(theirs))) = (ours, theirs) {
+        let empty = Map::new();
+        let base = base.and_then(Value::as_object).unwrap_or(&empty);
+        let mut merged = Map::new();
+        // our key order first, then keys only they added
+        for key in ours
+            .keys()
+            .chain(theirs.keys().filter(|k| !ours.contains_key(*k)))
+        {
+            let child = format!("{pointer}/{}", key.replace('~', "~0").replace('/', "~1"));
+            if let Some(value) = merge_at(
+                child,
+                base.get(key),
+                ours.get(key),
+                theirs.get(key),
+                conflicts,
+            ) {
+                merged.insert(key.clone(), value);
+            }
+        }
+        return Some(Value::Object(merged));
+    }
+    conflicts.push(KeyConflict {
+        pointer,
+        base: base.cloned(),
+        ours: ours.cloned(),
+        theirs: theirs.cloned(),
+    });
+    ours.cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_disjoint_changes_merge_cleanly() {
+        let base = json!({"theme": "light", "week": {"start": 1, "numbers": false}, "old": 1});
+        let ours = json!({"theme": "dark", "week": {"start": 1, "numbers": false}, "old": 1});
+        let theirs = json!({"theme": "light", "week": {"start": 1, "numbers": true}, "new": [1]});
+        let result = merge(&base, &ours, &theirs);
+        assert!(result.is_clean());
+        assert_eq!(
+            result.merged,
+            json!({"theme": "dark", "week": {"start": 1, "numbers": true}, "new": [1]})
+        );
+    }
+
+    #[test]
+    fn test_only_clashing_keys_conflict() {
+        let base = json!({"theme": "light", "week": {"start": 1}, "list": [1]});
+        let ours = json!({"theme": "dark", "week": {"start": 0}, "list": [1, 2]});
+        let theirs = json!({"theme": "light", "week": {"start": 6}, "list": [1, 3]});
+        let result = merge(&base, &ours, &theirs);
+        assert_eq!(result.merged, ours);
+        assert_eq!(
+            result
+                .conflicts
+                .iter()
+                .map(|c| c.pointer.as_str())
+                .collect::<Vec<_>>(),
+            ["/week/start", "/list"]
+        );
+        assert_eq!(result.conflicts[0].theirs, Some(json!(6)));
+
+        // a key deleted on one side and changed on the other
+        let result = merge(&json!({"a/b": 1}), &json!({}), &json!({"a/b": 2}));
+        assert_eq!(result.conflicts[0].pointer, "/a~1b");
+        assert_eq!(result.conflicts[0].ours, None);
+        assert_eq!(result.merged, json!({}));
+    }
+}
@@ -0,0 +1,383 @@
+//! Merkle trees summarising a plugins directory.
+//!
+//! Every file is a leaf holding the BLAKE3 hash of its contents, and every
+//! directory a node whose hash covers the names, kinds and hashes of its
+//! children. Two directories are identical exactly when their root hashes
+//! are, and [`MerkleTree::diff`] only descends into directories whose hashes
+//! differ. Directories without any files are left out, since syncing never
+//! creates them.
+//!
+//! [`TreeCache`] keeps the last tree built for each directory and reuses the
+//! leaf of every file whose size, modification time and inode are unchanged,
+//! so summarising an unchanged directory does not read any file.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use color_eyre::eyre::Result;
+use ignore::Walk;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::atomic::write_atomic;
+use crate::cryptography::cache::FileKey;
+use crate::logging::get_data_dir;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleNode {
+    hash: [u8; 32],
+    kind: NodeKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum NodeKind {
+    File(FileKey),
+    Dir(BTreeMap<String, MerkleNode>),
+}
+
+impl MerkleNode {
+    fn empty_dir() -> Self {
+        let mut node = Self {
+            hash: [0; 32],
+            kind: NodeKind::Dir(BTreeMap::new()),
+        };
+        node.rehash();
+        node
+    }
+
+    /// BLAKE3 hash of a file's contents, or the node hash of a directory.
+    pub fn hash(&self) -> [u8; 32] {
+        self.hash
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(self.kind, NodeKind::File(_))
+    }
+
+    /// Size of a file when its leaf was built. `None` for directories.
+    pub fn file_size(&self) -> Option<u64> {
+        match &self.kind {
+            NodeKind::File(key) => Some(key.size),
+            NodeKind::Dir(_) => None,
+        }
+    }
+
+    /// Recomputes the hashes of this directory and every directory below it
+    /// from their children. File hashes are left alone.
+    fn rehash(&mut self) {
+        let NodeKind::Dir(children) = &mut self.kind else {
+            return;
+        };
+        let mut hasher = blake3::Hasher::new();
+        for (name, child) in children.iter_mut() {
+            child.rehash();
+            hasher.update(&(name.len() as u64).to_le_bytes());
+            hasher.update(name.as_bytes());
+            hasher.update(&[child.is_file() as u8]);
+            hasher.update(&child.hash);
+        }
+        self.hash = *hasher.finalize().as_bytes();
+    }
+
+    fn files(&self, path: &Path, out: &mut Vec<PathBuf>) {
+        match &self.kind {
+            NodeKind::File(_) => out.push(path.to_path_buf()),
+            NodeKind::Dir(children) => {
+                for (name, child) in children {
+                    child.files(&path.join(name), out);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleTree {
+    root: MerkleNode,
+}
+
+impl MerkleTree {
+    /// Hashes every file under `dir`. A missing directory gives an empty tree.
+    pub fn build(dir: &Path) -> Result<Self> {
+        Self::build_reusing(dir, None)
+    }
+
+    /// Builds the tree of `dir`, taking the leaf of any file whose metadata
+    /// still matches its leaf in `previous` instead of reading the file.
+    fn build_reusing(dir: &Path, previous: Option<&MerkleTree>) -> Result<Self> {
+        let mut root = MerkleNode::empty_dir();
+        if !dir.is_dir() {
+            return Ok(Self { root });
+        }
+        for entry in Walk::new(dir) {
+            let entry = entry?;
+            if entry.depth() == 0 || entry.file_type().is_some_and(|t| t.is_dir()) {
+                continue;
+            }
+            let rel = entry.path().strip_prefix(dir)?;
+            let key = FileKey::for_path(entry.path())?;
+            let hash = match previous.and_then(|tree| tree.get(rel)) {
+                Some(MerkleNode {
+                    hash,
+                    kind: NodeKind::File(cached),
+                }) if *cached == key => *hash,
+                _ => hash_file(entry.path())?,
+            };
+            insert(
+                &mut root,
+                rel,
+                MerkleNode {
+                    hash,
+                    kind: NodeKind::File(key),
+                },
+            );
+        }
+        root.rehash();
+        Ok(Self { root })
+    }
+
+    /// Tree of files that are not on disk here, given as relative path, size
+    /// and content hash. Useful for trees received from elsewhere and for
+    /// planning without touching the disk.
+    pub fn from_hashes(files: impl IntoIterator<Item = (PathBuf, u64, [u8; 32])>) -> Self {
+        let mut root = MerkleNode::empty_dir();
+        for (rel, size, hash) in files {
+            let key = FileKey {
+                path: rel.clone(),
+                size,
+                mtime: SystemTime::UNIX_EPOCH,
+                inode: 0,
+            };
+            insert(
+                &mut root,
+                &rel,
+                MerkleNode {
+                    hash,
+                    kind: NodeKind::File(key),
+                },
+            );
+        }
+        root.rehash();
+        Self { root }
+    }
+
+    pub fn root_hash(&self) -> [u8; 32] {
+        self.root.hash
+    }
+
+    /// Node at `rel`, a path relative to the tree's directory.
+    pub fn get(&self, rel: &Path) -> Option<&MerkleNode> {
+        let mut node = &self.root;
+        for component in rel.components() {
+            let NodeKind::Dir(children) = &node.kind else {
+                return None;
+            };
+            node = children.get(component.as_os_str().to_str()?)?;
+        }
+        Some(node)
+    }
+
+    /// Relative paths of every file in the tree, in sorted order.
+    pub fn files(&self) -> Vec<PathBuf> {
+        let mut out = Vec::new();
+        self.root.files(Path::new(""), &mut out);
+        out
+    }
+
+    /// Relative paths of the files that are in only one of the trees or
+    /// differ between them, in sorted order. Directories with equal hashes
+    /// are skipped without looking inside.
+    pub fn diff(&self, other: &MerkleTree) -> Vec<PathBuf> {
+        let mut out = Vec::new();
+        diff_nodes(Some(&self.root), Some(&other.root), Path::new(""), &mut out);
+        out.sort();
+        out.dedup();
+        out
+    }
+}
+
+fn diff_nodes(a: Option<&MerkleNode>, b: Option<&MerkleNode>, path: &Path, out: &mut Vec<PathBuf>) {
+    if let (Some(a), Some(b)) = (a, b)
+        && a.hash == b.hash
+        && a.is_file() == b.is_file()
+    {
+        return;
+    }
+    match (a.map(|n| &n.kind), b.map(|n| &n.kind)) {
+        (Some(NodeKind::Dir(a)), Some(NodeKind::Dir(b))) => {
+            let names: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+            for name in names {
+                diff_nodes(a.get(name), b.get(name), &path.join(name), out);
+            }
+        }
+        (Some(NodeKind::File(_)), Some(NodeKind::File(_))) => out.push(path.to_path_buf()),
+        _ => {
+            // added, removed, or a file replaced by a directory
+            for node in a.into_iter().chain(b) {
+                node.files(path, out);
+            }
+        }
+    }
+}
+
+fn insert(root: &mut MerkleNode, rel: &Path, leaf: MerkleNode) {
+    let mut node = root;
+    let mut components = rel.iter().peekable();
+    while let Some(name) = components.next() {
+        let name = name.to_string_lossy().into_owned();
+        if !matches!(node.kind, NodeKind::Dir(_)) {
+            *node = MerkleNode::empty_dir();
+        }
+        let NodeKind::Dir(children) = &mut node.kind else {
+            unreachable!("node was just made a directory");
+        };
+        if components.peek().is_none() {
+            children.insert(name, leaf);
+            return;
+        }
+        node = children.entry(name).or_insert_with(MerkleNode::empty_dir);
+    }
+}
+
+/// BLAKE3 hash of the contents of the file at `path`.
+pub fn hash_file(path: &Path) -> io::Result<[u8; 32]> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// On-disk cache of the last [`MerkleTree`] built for each directory.
+#[derive(Debug, Clone)]
+pub struct TreeCache {
+    dir: PathBuf,
+}
+
+impl Default for TreeCache {
+    fn default() -> Self {
+        Self::new(get_data_dir().join("trees"))
+    }
+}
+
+impl TreeCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Returns the tree of `root`, only reading files whose metadata changed
+    /// since the cached tree was built.
+    pub fn tree_for(&self, root: &Path) -> Result<MerkleTree> {
+        if !root.is_dir() {
+            return MerkleTree::build(root);
+        }
+        let root = fs::canonicalize(root)?;
+        let entry_path = self.entry_path(&root);
+        let previous = self.load(&entry_path);
+        let tree = MerkleTree::build_reusing(&root, previous.as_ref())?;
+        debug!(
+            root = ?root,
+            hash = %blake3::Hash::from(tree.root_hash()).to_hex(),
+            "Built tree"
+        );
+        if previous.as_ref() != Some(&tree)
+            && let Err(e) = write_atomic(&entry_path, |out| {
+                serde_json::to_writer(out, &tree)?;
+                Ok(())
+            })
+        {
+            warn!(root = ?root, "Could not store tree: {}", e);
+        }
+        Ok(tree)
+    }
+
+    fn entry_path(&self, root: &Path) -> PathBuf {
+        let id = xxh3_64(root.as_os_str().as_encoded_bytes());
+        self.dir.join(format!("{id:016x}.json"))
+    }
+
+    fn load(&self, entry_path: &Path) -> Option<MerkleTree> {
+        let contents = fs::read(entry_path).ok()?;
+        match serde_json::from_slice(&contents) {
+            Ok(tree) => Some(tree),
+            Err(e) => {
+                warn!(entry = ?entry_path, "Ignoring unreadable tree cache entry: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(root: &Path, rel: &str, contents: &str) {
+        let path = root.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_diff_yields_only_differing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        for root in [&a, &b] {
+            write(root, "calendar/main.js", "console.log(1)");
+            write(root, "calendar/manifest.json", "{}");
+            write(root, "dataview/main.js", "console.log(2)");
+        }
+        let (tree_a, tree_b) = (
+            MerkleTree::build(&a).unwrap(),
+            MerkleTree::build(&b).unwrap(),
+        );
+        assert_eq!(tree_a.root_hash(), tree_b.root_hash());
+        assert!(tree_a.diff(&tree_b).is_empty());
+
+        write(&b, "calendar/main.js", "console.log(3)");
+        write(&b, "kanban/main.js", "board()");
+        fs::remove_file(b.join("dataview/main.js")).unwrap();
+        let tree_b = MerkleTree::build(&b).unwrap();
+        assert_ne!(tree_a.root_hash(), tree_b.root_hash());
+        assert_eq!(
+            tree_a.diff(&tree_b),
+            ["calendar/main.js", "dataview/main.js", "kanban/main.js"].map(PathBuf::from)
+        );
+        assert!(tree_b.get(Path::new("kanban/main.js")).unwrap().is_file());
+        assert!(tree_b.get(Path::new("dataview")).is_none());
+
+        let missing = MerkleTree::build(&dir.path().join("missing")).unwrap();
+        assert_eq!(missing.diff(&tree_a), tree_a.files());
+    }
+
+    #[test]
+    fn test_cache_skips_unchanged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("plugins");
+        write(&root, "calendar/main.js", "console.log(1)");
+        let cache = TreeCache::new(dir.path().join("trees"));
+        let first = cache.tree_for(&root).unwrap();
+
+        // same size, inode and mtime: the cached leaf is trusted
+        let path = root.join("calendar/main.js");
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, "console.log(2)").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        assert_eq!(cache.tree_for(&root).unwrap(), first);
+
+        write(&root, "calendar/main.js", "console.log(22)");
+        let changed = cache.tree_for(&root).unwrap();
+        assert_eq!(
+            changed.diff(&first),
+            vec![PathBuf::from("calendar/main.js")]
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! Content-addressed store of file contents.
+//!
+//! Every object is stored once under the hex BLAKE3 hash of its contents, in
+//! `objects` in the data dir, so keeping the same file many times costs the
+//! space of one copy. The hashes are the same as the leaf hashes of a
+//! [`MerkleTree`](crate::merkle::MerkleTree).
+//!
+//! Plugin settings often hold API tokens, so once an [`EncryptionKey`] is set
+//! in the [`KeyStore`], objects are stored sealed with it. They are still
+//! named by the hash of their plain contents, and objects stored before the
+//! key was set can still be read.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{Result, eyre};
+use tracing::{debug, warn};
+
+use crate::atomic::write_atomic;
+use crate::cryptography::encryption::EncryptionKey;
+use crate::cryptography::identity::{AuthError, KeyStore};
+use crate::cryptography::wire::{PayloadKind, WireError};
+use crate::logging::get_data_dir;
+
+#[derive(Debug, Clone)]
+pub struct ObjectStore {
+    dir: PathBuf,
+    key: Option<EncryptionKey>,
+}
+
+/// Uses the encryption key of the default [`KeyStore`], if one was set.
+impl Default for ObjectStore {
+    fn default() -> Self {
+        let key = KeyStore::default().encryption_key().unwrap_or_else(|e| {
+            warn!("Storing objects unencrypted, could not load the key: {}", e);
+            None
+        });
+        Self {
+            key,
+            ..Self::new(get_data_dir().join("objects"))
+        }
+    }
+}
+
+impl ObjectStore {
+    /// A store that keeps objects unencrypted.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            key: None,
+        }
+    }
+
+    /// A store that seals new objects with `key`.
+    pub fn encrypted(dir: impl Into<PathBuf>, key: EncryptionKey) -> Self {
+        Self {
+            dir: dir.into(),
+            key: Some(key),
+        }
+    }
+
+    /// Where the object with `hash` is, or would be, stored.
+    pub fn path_of(&self, hash: &[u8; 32]) -> PathBuf {
+        let hex = blake3::Hash::from(*hash).to_hex();
+        self.dir.join(&hex[..2]).join(&hex[2..])
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.path_of(hash).is_file()
+    }
+
+    /// Stores everything `reader` yields and returns its hash. The contents
+    /// are hashed as they are written, so a file that changes while it is
+    /// read is still stored under the right hash.
+    pub fn insert(&self, mut reader: impl Read) -> Result<[u8; 32]> {
+        if let Some(key) = &self.key {
+            let mut contents = Vec::new();
+            reader.read_to_end(&mut contents)?;
+            let hash = *blake3::hash(&contents).as_bytes();
+            let path = self.path_of(&hash);
+            if !path.exists() {
+                let sealed = key.seal(PayloadKind::Object, &contents);
+                write_atomic(&path, |out| Ok(out.write_all(&sealed)?))?;
+            }
+            return Ok(hash);
+        }
+        fs::create_dir_all(&self.dir)?;
+        let mut tmp = tempfile::Builder::new()
+            .prefix(".object.")
+            .tempfile_in(&self.dir)?;
+        let mut hasher = blake3::Hasher::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            tmp.write_all(&buf[..n])?;
+        }
+        let hash = *hasher.finalize().as_bytes();
+        let path = self.path_of(&hash);
+        if !path.exists() {
+            fs::create_dir_all(path.parent().expect("objects live in a fan-out dir"))?;
+            tmp.as_file().sync_all()?;
+            tmp.persist(&path)?;
+        }
+        Ok(hash)
+    }
+
+    pub fn insert_file(&self, path: &Path) -> Result<[u8; 32]> {
+        self.insert(fs::File::open(path)?)
+    }
+
+    /// The contents of the object `hash`, decrypted if it was sealed.
+    pub fn read(&self, hash: &[u8; 32]) -> Result<Vec<u8>> {
+        let stored = fs::read(self.path_of(hash))?;
+        let Some(key) = &self.key else {
+            return Ok(stored);
+        };
+        let contents = match key.open(&stored, PayloadKind::Object) {
+            Ok(contents) => contents,
+            // stored before the key was set
+            Err(AuthError::Malformed(WireError::BadMagic)) => stored,
+            Err(e) => return Err(eyre!("could not open object: {e}")),
+        };
+        if blake3::hash(&contents).as_bytes() != hash {
+            return Err(eyre!(
+                "object {} is corrupt",
+                blake3::Hash::from(*hash).to_hex()
+            ));
+        }
+        Ok(contents)
+    }
+
+    /// Atomically replaces `target` with the object `hash`.
+    pub fn restore_to(&self, hash: &[u8; 32], target: &Path) -> Result<()> {
+        if self.key.is_some() {
+            let contents = self.read(hash)?;
+            return write_atomic(target, |out| Ok(out.write_all(&contents)?));
+        }
+        let mut object = fs::File::open(self.path_of(hash))?;
+        write_atomic(target, |out| {
+            io::copy(&mut object, out)?;
+            Ok(())
+        })
+    }
+
+    /// Removes every stored object whose hash is not in `live` and returns
+    /// how many were removed. Objects still being inserted are left alone.
+    pub fn retain(&self, live: &HashSet<[u8; 32]>) -> Result<usize> {
+        let fan_outs = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut removed = 0;
+        for fan_out in fan_outs {
+            let fan_out = fan_out?;
+            if !fan_out.file_type()?.is_dir() {
+                continue;
+            }
+            let prefix = fan_out.file_name();
+            for entry in fs::read_dir(fan_out.path())? {
+                let entry = entry?;
+                let hex = format!(
+                    "{}{}",
+                    prefix.to_string_lossy(),
+                    entry.file_name().to_string_lossy()
+                );
+                let Ok(hash) = blake3::Hash::from_hex(&hex) else {
+                    continue;
+                };
+                if !live.contains(hash.as_bytes()) {
+                    fs::remove_file(entry.path())?;
+                    debug!(object = %hex, "Removed unused object");
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_objects_are_deduplicated() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ObjectStore::new(dir.path());
+        let a = store.insert(&b"plugin code"[..]).unwrap();
+        let b = store.insert(&b"plugin code"[..]).unwrap();
+        let c = store.insert(&b"other code"[..]).unwrap();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(a, *blake3::hash(b"plugin code").as_bytes());
+        assert_eq!(store.read(&a).unwrap(), b"plugin code");
+
+        let objects = count_objects(dir.path());
+        assert_eq!(objects, 2);
+
+        let target = dir.path().join("out").join("main.js");
+        store.restore_to(&c, &target).unwrap();
+        assert_eq!(fs::read(target).unwrap(), b"other code");
+    }
+
+    #[test]
+    fn test_unused_objects_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ObjectStore::new(dir.path());
+        let kept = store.insert(&b"still used"[..]).unwrap();
+        let unused = store.insert(&b"not used"[..]).unwrap();
+        fs::write(dir.path().join(".object.pending"), "partial").unwrap();
+
+        assert_eq!(store.retain(&HashSet::from([kept])).unwrap(), 1);
+        assert!(store.contains(&kept));
+        assert!(!store.contains(&unused));
+        assert!(dir.path().join(".object.pending").exists());
+        assert_eq!(store.retain(&HashSet::from([kept])).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_objects_are_sealed_with_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = ObjectStore::new(dir.path()).insert(&b"before"[..]).unwrap();
+        let key = EncryptionKey::from_passphrase("hunter2", [7; 16]).unwrap();
+        let store = ObjectStore::encrypted(dir.path(), key);
+
+        let secret = store.insert(&br#"{"token":"abc"}"#[..]).unwrap();
+        assert_eq!(secret, *blake3::hash(br#"{"token":"abc"}"#).as_bytes());
+        let stored = fs::read(store.path_of(&secret)).unwrap();
+        assert!(!stored.windows(3).any(|w| w == b"abc"));
+        assert_eq!(store.read(&secret).unwrap(), br#"{"token":"abc"}"#);
+        let target = dir.path().join("out").join("data.json");
+        store.restore_to(&secret, &target).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), br#"{"token":"abc"}"#);
+
+        // objects stored before the key was set still read
+        assert_eq!(store.read(&old).unwrap(), b"before");
+        let other = EncryptionKey::from_passphrase("hunter2", [8; 16]).unwrap();
+        assert!(
+            ObjectStore::encrypted(dir.path(), other)
+                .read(&secret)
+                .is_err()
+        );
+    }
+
+    fn count_objects(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.is_dir())
+            .map(|p| fs::read_dir(p).unwrap().count())
+            .sum()
+    }
+}
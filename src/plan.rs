@@ -0,0 +1,564 @@
+//! Deciding what a sync will do, separately from doing it.
+//!
+//! A [`SyncPlan`] lists every file of a vault sync and what will happen to
+//! it. [`SyncPlan::from_trees`] makes the decisions from two [`MerkleTree`]s
+//! alone, so they can be tested without files; [`plan_vault`] gathers the
+//! trees and delta sizes from disk, and [`execute_plan`] carries a plan out.
+//!
+//! [`plan_vault`]: crate::plan_vault
+//! [`execute_plan`]: crate::execute_plan
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cryptography::delta::{Delta, DeltaStats};
+use crate::merkle::{MerkleNode, MerkleTree};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PlannedAction {
+    /// Copy a file that the destination does not have.
+    Create,
+    /// Patch a destination file whose contents differ. The delta is filled in
+    /// by [`plan_vault`](crate::plan_vault) when it could be computed, and
+    /// kept whole in [`PlannedFile::patch`].
+    Update { delta: Option<DeltaStats> },
+    /// Remove a destination file that the source no longer has.
+    Delete,
+    /// Leave an identical file alone.
+    Skip,
+    /// Leave alone a destination file that only the destination changed, or
+    /// added, since the last sync.
+    Keep,
+    /// Both vaults changed the file since it was last synced between them,
+    /// so neither copy may overwrite the other. Holds the content hashes of
+    /// the last synced version, `None` if the destination's copy was never
+    /// synced, of the destination's copy and of the source's copy, `None` if
+    /// the source deleted it.
+    Conflict {
+        base: Option<[u8; 32]>,
+        ours: [u8; 32],
+        theirs: Option<[u8; 32]>,
+    },
+    /// Plugin settings changed in both vaults whose last synced version is
+    /// known, so the changes can be merged key by key.
+    Merge {
+        base: [u8; 32],
+        ours: [u8; 32],
+        theirs: [u8; 32],
+    },
+}
+
+impl PlannedAction {
+    /// Action for a file with the content hash `src` in the source and `dst`
+    /// in the destination, `None` where it is missing. Returns `None` when
+    /// the file is in neither.
+    pub fn between(src: Option<[u8; 32]>, dst: Option<[u8; 32]>) -> Option<Self> {
+        match (src, dst) {
+            (Some(src), Some(dst)) if src == dst => Some(PlannedAction::Skip),
+            (Some(_), Some(_)) => Some(PlannedAction::Update { delta: None }),
+            (Some(_), None) => Some(PlannedAction::Create),
+            (None, Some(_)) => Some(PlannedAction::Delete),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlannedFile {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub action: PlannedAction,
+    /// Size of the source file, or of the destination file for deletes.
+    pub bytes: u64,
+    /// The delta of an update, so carrying out the plan does not compute it
+    /// again. Not serialized.
+    #[serde(skip)]
+    pub patch: Option<Arc<Delta>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncPlan {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// The vault's `community-plugins.json`, which lists enabled plugins.
+    /// `None` when the source vault has none; the destination's list is never
+    /// deleted.
+    pub community_plugins: Option<PlannedFile>,
+    /// Files under `.obsidian/plugins`, in path order.
+    pub files: Vec<PlannedFile>,
+}
+
+impl SyncPlan {
+    /// Plans syncing the plugins of the vault `from` to the vault `to`, given
+    /// the trees of both plugin directories and the action already decided
+    /// for `community-plugins.json`. Does not touch the disk.
+    pub fn from_trees(
+        from: &Path,
+        to: &Path,
+        tree_from: &MerkleTree,
+        tree_to: &MerkleTree,
+        community_plugins: Option<(PlannedAction, u64)>,
+    ) -> Self {
+        let plugins_from = plugins_dir(from);
+        let plugins_to = plugins_dir(to);
+        let changed = if tree_from.root_hash() == tree_to.root_hash() {
+            Vec::new()
+        } else {
+            tree_from.diff(tree_to)
+        };
+
+        let mut files = Vec::new();
+        for rel_path in tree_from.files() {
+            if changed.binary_search(&rel_path).is_ok() {
+                continue;
+            }
+            let bytes = tree_from.get(&rel_path).and_then(|n| n.file_size());
+            files.push(PlannedFile {
+                from: plugins_from.join(&rel_path),
+                to: plugins_to.join(&rel_path),
+                action: PlannedAction::Skip,
+                bytes: bytes.unwrap_or_default(),
+                patch: None,
+            });
+        }
+        for rel_path in changed {
+            let src = tree_from.get(&rel_path).filter(|n| n.is_file());
+            let dst = tree_to.get(&rel_path).filter(|n| n.is_file());
+            let Some(action) = PlannedAction::between(src.map(|n| n.hash()), dst.map(|n| n.hash()))
+            else {
+                continue;
+            };
+            let bytes = src.or(dst).and_then(|n| n.file_size());
+            files.push(PlannedFile {
+                from: plugins_from.join(&rel_path),
+                to: plugins_to.join(&rel_path),
+                action,
+                bytes: bytes.unwrap_or_default(),
+                patch: None,
+            });
+        }
+        // sorted by path, except that the files of a directory that is now a
+        // file in `from` are deleted before the file is created in its place
+        files.sort_by_cached_key(|file| {
+            let rel = file.to.strip_prefix(&plugins_to).unwrap_or(&file.to);
+            let replaced_by = rel
+                .ancestors()
+                .skip(1)
+                .find(|dir| tree_from.get(dir).is_some_and(MerkleNode::is_file));
+            match replaced_by {
+                Some(path) => (plugins_to.join(path), false, file.to.clone()),
+                None => (file.to.clone(), true, file.to.clone()),
+            }
+        });
+
+        Self {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            community_plugins: community_plugins.map(|(action, bytes)| PlannedFile {
+                from: community_plugins_file(from),
+                to: community_plugins_file(to),
+                action,
+                bytes,
+                patch: None,
+            }),
+            files,
+        }
+    }
+
+    /// `community-plugins.json` followed by the plugin files.
+    pub fn all_files(&self) -> impl Iterator<Item = &PlannedFile> {
+        self.community_plugins.iter().chain(&self.files)
+    }
+
+    pub fn all_files_mut(&mut self) -> impl Iterator<Item = &mut PlannedFile> {
+        self.community_plugins.iter_mut().chain(&mut self.files)
+    }
+
+    pub fn creates(&self) -> impl Iterator<Item = &PlannedFile> {
+        self.all_files()
+            .filter(|f| f.action == PlannedAction::Create)
+    }
+
+    pub fn updates(&self) -> impl Iterator<Item = &PlannedFile> {
+        self.all_files()
+            .filter(|f| matches!(f.action, PlannedAction::Update { .. }))
+    }
+
+    pub fn deletes(&self) -> impl Iterator<Item = &PlannedFile> {
+        self.all_files()
+            .filter(|f| f.action == PlannedAction::Delete)
+    }
+
+    pub fn skips(&self) -> impl Iterator<Item = &PlannedFile> {
+        self.all_files().filter(|f| f.action == PlannedAction::Skip)
+    }
+
+    pub fn keeps(&self) -> impl Iterator<Item = &PlannedFile> {
+        self.all_files().filter(|f| f.action == PlannedAction::Keep)
+    }
+
+    pub fn conflicts(&self) -> impl Iterator<Item = &PlannedFile> {
+        self.all_files()
+            .filter(|f| matches!(f.action, PlannedAction::Conflict { .. }))
+    }
+
+    pub fn merges(&self) -> impl Iterator<Item = &PlannedFile> {
+        self.all_files()
+            .filter(|f| matches!(f.action, PlannedAction::Merge { .. }))
+    }
+
+    /// Whether carrying out the plan would write or remove anything.
+    pub fn is_noop(&self) -> bool {
+        self.all_files()
+            .all(|f| matches!(f.action, PlannedAction::Skip | PlannedAction::Keep))
+    }
+}
+
+/// One summary line, then one line for every file that would change.
+impl fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {}: {} to create, {} to update, {} to delete, {} unchanged",
+            self.from.display(),
+            self.to.display(),
+            self.creates().count(),
+            self.updates().count(),
+            self.deletes().count(),
+            self.skips().count()
+        )?;
+        let keeps = self.keeps().count();
+        if keeps > 0 {
+            write!(f, ", {keeps} newer here")?;
+        }
+        let merges = self.merges().count();
+        if merges > 0 {
+            write!(f, ", {merges} to merge")?;
+        }
+        let conflicts = self.conflicts().count();
+        if conflicts > 0 {
+            write!(f, ", {conflicts} in conflict")?;
+        }
+        for file in self.all_files() {
+            match &file.action {
+                PlannedAction::Skip => {}
+                PlannedAction::Keep => write!(
+                    f,
+                    "\n  keep {} (changed only in this vault)",
+                    file.to.display()
+                )?,
+                PlannedAction::Create => {
+                    write!(f, "\n  create {} ({} bytes)", file.to.display(), file.bytes)?
+                }
+                PlannedAction::Update { delta: Some(stats) } => {
+                    write!(f, "\n  update {} ({stats})", file.to.display())?
+                }
+                PlannedAction::Update { delta: None } => {
+                    write!(f, "\n  update {} ({} bytes)", file.to.display(), file.bytes)?
+                }
+                PlannedAction::Delete => {
+                    write!(f, "\n  delete {} ({} bytes)", file.to.display(), file.bytes)?
+                }
+                PlannedAction::Merge { .. } => write!(
+                    f,
+                    "\n  merge {} (changed in both vaults)",
+                    file.to.display()
+                )?,
+                PlannedAction::Conflict { base: None, .. } => write!(
+                    f,
+                    "\n  conflict {} (never synced, differs from the source)",
+                    file.to.display()
+                )?,
+                PlannedAction::Conflict { .. } => write!(
+                    f,
+                    "\n  conflict {} (changed in both vaults)",
+                    file.to.display()
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Limits on how much a single sync may delete. A source vault whose plugins
+/// folder is briefly empty or half written by another tool would otherwise
+/// wipe the plugins of every other vault.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DeletionGuard {
+    /// Most plugin files one sync may delete.
+    pub max_files: usize,
+    /// Largest share of the destination's plugin files, in percent, that
+    /// one sync may delete.
+    pub max_percent: f64,
+    /// Refuse to delete anything when the source has no plugin files at all.
+    pub refuse_empty_source: bool,
+}
+
+impl Default for DeletionGuard {
+    fn default() -> Self {
+        Self {
+            max_files: 10,
+            max_percent: 50.0,
+            refuse_empty_source: true,
+        }
+    }
+}
+
+impl DeletionGuard {
+    /// A guard that lets every plan through.
+    pub fn disabled() -> Self {
+        Self {
+            max_files: usize::MAX,
+            max_percent: 100.0,
+            refuse_empty_source: false,
+        }
+    }
+
+    /// Checks the deletions in `plan` against the limits.
+    pub fn check(&self, plan: &SyncPlan) -> Result<(), GuardViolation> {
+        let deletes = plan.deletes().count();
+        if deletes == 0 {
+            return Ok(());
+        }
+        let source_files = plan
+            .files
+            .iter()
+            .filter(|f| {
+                !matches!(
+                    f.action,
+                    PlannedAction::Delete | PlannedAction::Conflict { theirs: None, .. }
+                )
+            })
+            .count();
+        // every destination plugin file is either kept, replaced or deleted
+        let destination_files = plan
+            .files
+            .iter()
+            .filter(|f| f.action != PlannedAction::Create)
+            .count();
+        if self.refuse_empty_source && source_files == 0 {
+            return Err(GuardViolation::EmptySource { deletes });
+        }
+        if deletes > self.max_files {
+            return Err(GuardViolation::TooManyFiles {
+                deletes,
+                limit: self.max_files,
+            });
+        }
+        let percent = deletes as f64 * 100.0 / destination_files as f64;
+        if percent > self.max_percent {
+            return Err(GuardViolation::TooLargeShare {
+                deletes,
+                destination_files,
+                limit: self.max_percent,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GuardViolation {
+    /// The source vault has no plugin files.
+    EmptySource {
+        deletes: usize,
+    },
+    TooManyFiles {
+        deletes: usize,
+        limit: usize,
+    },
+    TooLargeShare {
+        deletes: usize,
+        destination_files: usize,
+        limit: f64,
+    },
+}
+
+impl fmt::Display for GuardViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuardViolation::EmptySource { deletes } => write!(
+                f,
+                "the source vault has no plugin files, refusing to delete {deletes} files"
+            ),
+            GuardViolation::TooManyFiles { deletes, limit } => write!(
+                f,
+                "sync would delete {deletes} files, more than the limit of {limit}"
+            ),
+            GuardViolation::TooLargeShare {
+                deletes,
+                destination_files,
+                limit,
+            } => write!(
+                f,
+                "sync would delete {deletes} of {destination_files} plugin files, more than {limit}%"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GuardViolation {}
+
+pub fn plugins_dir(vault: &Path) -> PathBuf {
+    vault.join(".obsidian").join("plugins")
+}
+
+pub fn community_plugins_file(vault: &Path) -> PathBuf {
+    vault.join(".obsidian").join("community-plugins.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(files: &[(&str, &str)]) -> MerkleTree {
+        MerkleTree::from_hashes(files.iter().map(|(path, contents)| {
+            (
+                PathBuf::from(path),
+                contents.len() as u64,
+                *blake3::hash(contents.as_bytes()).as_bytes(),
+            )
+        }))
+    }
+
+    #[test]
+    fn test_plan_from_trees() {
+        let from = tree(&[
+            ("calendar/main.js", "v2"),
+            ("calendar/manifest.json", "{}"),
+            ("kanban/main.js", "board"),
+        ]);
+        let to = tree(&[
+            ("calendar/main.js", "v1"),
+            ("calendar/manifest.json", "{}"),
+            ("dataview/main.js", "query"),
+        ]);
+        let (a, b) = (Path::new("/vaults/a"), Path::new("/vaults/b"));
+        let plan = SyncPlan::from_trees(a, b, &from, &to, Some((PlannedAction::Skip, 2)));
+
+        let actions: Vec<(String, PlannedAction)> = plan
+            .files
+            .iter()
+            .map(|f| {
+                let rel = f.to.strip_prefix(plugins_dir(b)).unwrap();
+                (rel.display().to_string(), f.action)
+            })
+            .collect();
+        pretty_assertions::assert_eq!(
+            actions,
+            vec![
+                (
+                    "calendar/main.js".to_owned(),
+                    PlannedAction::Update { delta: None }
+                ),
+                ("calendar/manifest.json".to_owned(), PlannedAction::Skip),
+                ("dataview/main.js".to_owned(), PlannedAction::Delete),
+                ("kanban/main.js".to_owned(), PlannedAction::Create),
+            ]
+        );
+        assert_eq!(plan.deletes().next().unwrap().bytes, 5);
+        assert!(!plan.is_noop());
+
+        let plan = SyncPlan::from_trees(a, b, &from, &from, Some((PlannedAction::Skip, 2)));
+        assert!(plan.is_noop());
+        assert_eq!(plan.skips().count(), 4);
+
+        let json = serde_json::to_string(&plan).unwrap();
+        assert_eq!(serde_json::from_str::<SyncPlan>(&json).unwrap(), plan);
+    }
+
+    #[test]
+    fn test_type_changes_delete_before_create() {
+        let (a, b) = (Path::new("/vaults/a"), Path::new("/vaults/b"));
+        let actions = |from: &MerkleTree, to: &MerkleTree| {
+            SyncPlan::from_trees(a, b, from, to, None)
+                .files
+                .iter()
+                .map(|f| {
+                    let rel = f.to.strip_prefix(plugins_dir(b)).unwrap();
+                    (rel.display().to_string(), f.action)
+                })
+                .collect::<Vec<_>>()
+        };
+        let file = tree(&[("calendar/lib", "code"), ("calendar/main.js", "v1")]);
+        let dir = tree(&[
+            ("calendar/lib/a.js", "a"),
+            ("calendar/lib/b.js", "b"),
+            ("calendar/main.js", "v1"),
+        ]);
+
+        // a directory replaced by a file
+        pretty_assertions::assert_eq!(
+            actions(&file, &dir),
+            vec![
+                ("calendar/lib/a.js".to_owned(), PlannedAction::Delete),
+                ("calendar/lib/b.js".to_owned(), PlannedAction::Delete),
+                ("calendar/lib".to_owned(), PlannedAction::Create),
+                ("calendar/main.js".to_owned(), PlannedAction::Skip),
+            ]
+        );
+        // a file replaced by a directory
+        pretty_assertions::assert_eq!(
+            actions(&dir, &file),
+            vec![
+                ("calendar/lib".to_owned(), PlannedAction::Delete),
+                ("calendar/lib/a.js".to_owned(), PlannedAction::Create),
+                ("calendar/lib/b.js".to_owned(), PlannedAction::Create),
+                ("calendar/main.js".to_owned(), PlannedAction::Skip),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deletion_guard() {
+        let (a, b) = (Path::new("/vaults/a"), Path::new("/vaults/b"));
+        let many: Vec<(String, String)> = (0..20)
+            .map(|i| (format!("plugin{i}/main.js"), format!("v{i}")))
+            .collect();
+        let many: Vec<(&str, &str)> = many.iter().map(|(p, c)| (p.as_str(), c.as_str())).collect();
+        let guard = DeletionGuard::default();
+
+        let empty = SyncPlan::from_trees(a, b, &tree(&[]), &tree(&many[..3]), None);
+        assert_eq!(
+            guard.check(&empty),
+            Err(GuardViolation::EmptySource { deletes: 3 })
+        );
+        assert_eq!(DeletionGuard::disabled().check(&empty), Ok(()));
+
+        let most = SyncPlan::from_trees(a, b, &tree(&many[..2]), &tree(&many), None);
+        assert_eq!(
+            guard.check(&most),
+            Err(GuardViolation::TooManyFiles {
+                deletes: 18,
+                limit: 10
+            })
+        );
+
+        let half = SyncPlan::from_trees(a, b, &tree(&many[..4]), &tree(&many[..10]), None);
+        assert!(matches!(
+            guard.check(&half),
+            Err(GuardViolation::TooLargeShare { deletes: 6, .. })
+        ));
+
+        let few = SyncPlan::from_trees(a, b, &tree(&many[..9]), &tree(&many[..10]), None);
+        assert_eq!(guard.check(&few), Ok(()));
+    }
+
+    #[test]
+    fn test_action_between() {
+        let (x, y) = (Some([1; 32]), Some([2; 32]));
+        assert_eq!(PlannedAction::between(x, x), Some(PlannedAction::Skip));
+        assert_eq!(
+            PlannedAction::between(x, y),
+            Some(PlannedAction::Update { delta: None })
+        );
+        assert_eq!(PlannedAction::between(x, None), Some(PlannedAction::Create));
+        assert_eq!(PlannedAction::between(None, y), Some(PlannedAction::Delete));
+        assert_eq!(PlannedAction::between(None, None), None);
+    }
+}
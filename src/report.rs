@@ -0,0 +1,315 @@
+//! What a sync did, file by file.
+//!
+//! [`sync_vault`](crate::sync_vault) returns a [`SyncReport`] and also keeps
+//! the latest report of every destination vault under `reports` in the data
+//! dir, where a status command can pick it up with [`latest_reports`].
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::atomic::write_atomic;
+use crate::cryptography::delta::DeltaStats;
+use crate::logging::get_data_dir;
+use crate::structs::vault_id;
+
+/// What syncing did to one destination file.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileOutcome {
+    /// The file already had the right contents and was not written.
+    Unchanged,
+    /// Only the destination changed the file since the last sync, so it was
+    /// left alone.
+    Kept,
+    Patched,
+    Created,
+    Deleted,
+    /// Syncing the file failed; the error is in [`FileReport::error`].
+    Failed,
+    /// Plugin settings changed in both vaults were merged key by key.
+    Merged,
+    /// The file was changed in both vaults. It was left alone, or, for
+    /// plugin settings, merged with the clashing keys kept as they were.
+    Conflict,
+}
+
+impl fmt::Display for FileOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FileOutcome::Unchanged => "unchanged",
+            FileOutcome::Kept => "kept",
+            FileOutcome::Patched => "patched",
+            FileOutcome::Created => "created",
+            FileOutcome::Deleted => "deleted",
+            FileOutcome::Failed => "failed",
+            FileOutcome::Merged => "merged",
+            FileOutcome::Conflict => "conflict",
+        };
+        f.pad(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileReport {
+    /// Destination path.
+    pub path: PathBuf,
+    pub outcome: FileOutcome,
+    /// Size of the file after syncing, or before it was deleted.
+    pub bytes: u64,
+    /// Bytes that had to be written from the source rather than reused from
+    /// the old destination file.
+    pub bytes_written: u64,
+    /// Set when the file was patched with a delta.
+    pub delta: Option<DeltaStats>,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+impl FileReport {
+    pub fn new(path: PathBuf, outcome: FileOutcome) -> Self {
+        Self {
+            path,
+            outcome,
+            bytes: 0,
+            bytes_written: 0,
+            delta: None,
+            duration: Duration::ZERO,
+            error: None,
+        }
+    }
+
+    pub fn failed(path: PathBuf, error: String) -> Self {
+        Self {
+            error: Some(error),
+            ..Self::new(path, FileOutcome::Failed)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncReport {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub started: SystemTime,
+    pub duration: Duration,
+    pub files: Vec<FileReport>,
+    /// Id of the trash batch holding the files this sync deleted or
+    /// overwrote, if it touched any.
+    #[serde(default)]
+    pub trash: Option<String>,
+}
+
+impl SyncReport {
+    pub fn new(from: PathBuf, to: PathBuf) -> Self {
+        Self {
+            from,
+            to,
+            started: SystemTime::now(),
+            duration: Duration::ZERO,
+            files: Vec::new(),
+            trash: None,
+        }
+    }
+
+    pub fn record(&mut self, file: FileReport) {
+        self.files.push(file);
+    }
+
+    pub fn with_outcome(&self, outcome: FileOutcome) -> impl Iterator<Item = &FileReport> {
+        self.files.iter().filter(move |f| f.outcome == outcome)
+    }
+
+    pub fn count(&self, outcome: FileOutcome) -> usize {
+        self.with_outcome(outcome).count()
+    }
+
+    pub fn created(&self) -> impl Iterator<Item = &FileReport> {
+        self.with_outcome(FileOutcome::Created)
+    }
+
+    /// Files that were patched with a delta.
+    pub fn updated(&self) -> impl Iterator<Item = &FileReport> {
+        self.with_outcome(FileOutcome::Patched)
+    }
+
+    pub fn deleted(&self) -> impl Iterator<Item = &FileReport> {
+        self.with_outcome(FileOutcome::Deleted)
+    }
+
+    /// Files that were already identical.
+    pub fn skipped(&self) -> impl Iterator<Item = &FileReport> {
+        self.with_outcome(FileOutcome::Unchanged)
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &FileReport> {
+        self.with_outcome(FileOutcome::Failed)
+    }
+
+    /// Files that were left alone because both vaults changed them.
+    pub fn conflicts(&self) -> impl Iterator<Item = &FileReport> {
+        self.with_outcome(FileOutcome::Conflict)
+    }
+
+    /// Whether any destination file was written or removed.
+    pub fn changed_anything(&self) -> bool {
+        self.files.iter().any(|f| {
+            !matches!(
+                f.outcome,
+                FileOutcome::Unchanged
+                    | FileOutcome::Kept
+                    | FileOutcome::Failed
+                    | FileOutcome::Conflict
+            )
+        })
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.files.iter().map(|f| f.bytes_written).sum()
+    }
+
+    /// Saves the report as the latest one for its destination vault.
+    pub fn save(&self) -> Result<()> {
+        self.save_in(&reports_dir())
+    }
+
+    fn save_in(&self, dir: &Path) -> Result<()> {
+        let id = vault_id(&self.to);
+        write_atomic(&dir.join(format!("{id:016x}.json")), |out| {
+            serde_json::to_writer_pretty(out, self)?;
+            Ok(())
+        })
+    }
+}
+
+/// One summary line, then one line for every file that was not skipped.
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} in {:.2?}: {} created, {} updated, {} deleted, {} skipped, {} failed, {} bytes written",
+            self.from.display(),
+            self.to.display(),
+            self.duration,
+            self.count(FileOutcome::Created),
+            self.count(FileOutcome::Patched),
+            self.count(FileOutcome::Deleted),
+            self.count(FileOutcome::Unchanged),
+            self.count(FileOutcome::Failed),
+            self.bytes_written()
+        )?;
+        let kept = self.count(FileOutcome::Kept);
+        if kept > 0 {
+            write!(f, ", {kept} newer here")?;
+        }
+        let merged = self.count(FileOutcome::Merged);
+        if merged > 0 {
+            write!(f, ", {merged} merged")?;
+        }
+        let conflicts = self.count(FileOutcome::Conflict);
+        if conflicts > 0 {
+            write!(f, ", {conflicts} in conflict")?;
+        }
+        if let Some(trash) = &self.trash {
+            write!(f, ", old files kept in trash {trash}")?;
+        }
+        for file in &self.files {
+            if file.outcome == FileOutcome::Unchanged {
+                continue;
+            }
+            write!(
+                f,
+                "\n  {:<9} {} ({} bytes, {:.2?})",
+                file.outcome,
+                file.path.display(),
+                file.bytes,
+                file.duration
+            )?;
+            if let Some(stats) = &file.delta {
+                write!(f, " delta: {stats}")?;
+            }
+            if let Some(error) = &file.error {
+                write!(f, " error: {error}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn reports_dir() -> PathBuf {
+    get_data_dir().join("reports")
+}
+
+/// The latest saved report of every destination vault.
+pub fn latest_reports() -> Result<Vec<SyncReport>> {
+    latest_reports_in(&reports_dir())
+}
+
+fn latest_reports_in(dir: &Path) -> Result<Vec<SyncReport>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reports = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        match serde_json::from_slice(&fs::read(&path)?) {
+            Ok(report) => reports.push(report),
+            Err(e) => warn!(report = ?path, "Ignoring unreadable report: {}", e),
+        }
+    }
+    reports.sort_by_key(|r: &SyncReport| r.to.clone());
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_renders_and_persists() {
+        let mut report =
+            SyncReport::new(PathBuf::from("/vaults/main"), PathBuf::from("/vaults/work"));
+        report.record(FileReport::new(
+            PathBuf::from("calendar/manifest.json"),
+            FileOutcome::Unchanged,
+        ));
+        report.record(FileReport {
+            bytes: 120,
+            bytes_written: 120,
+            ..FileReport::new(PathBuf::from("calendar/main.js"), FileOutcome::Created)
+        });
+        report.record(FileReport::failed(
+            PathBuf::from("dataview/main.js"),
+            "permission denied".to_owned(),
+        ));
+        assert!(report.changed_anything());
+        assert_eq!(report.skipped().count(), 1);
+        assert_eq!(report.errors().count(), 1);
+
+        let rendered = report.to_string();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(
+            lines[0].ends_with(
+                "1 created, 0 updated, 0 deleted, 1 skipped, 1 failed, 120 bytes written"
+            )
+        );
+        assert!(lines[1].starts_with("  created   calendar/main.js (120 bytes"));
+        assert!(lines[2].ends_with("error: permission denied"));
+
+        let dir = tempfile::tempdir().unwrap();
+        report.save_in(dir.path()).unwrap();
+        report.save_in(dir.path()).unwrap();
+        assert_eq!(latest_reports_in(dir.path()).unwrap(), vec![report]);
+    }
+}
@@ -0,0 +1,405 @@
+//! Snapshots of a vault's plugins, taken before syncing into it.
+//!
+//! A snapshot records the content hash of every plugin file and of
+//! `community-plugins.json`, with the contents kept in the [`ObjectStore`],
+//! so taking a snapshot of unchanged plugins writes nothing but its
+//! manifest. Snapshots of every vault synced in the same round share an id,
+//! which lets [`SnapshotStore::rollback`] put all of them back at once.
+//! Manifests live under `snapshots/<id>` in the data dir.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::{Result, eyre};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, instrument, warn};
+
+use crate::atomic::write_atomic;
+use crate::logging::get_data_dir;
+use crate::merkle::{TreeCache, hash_file};
+use crate::objects::ObjectStore;
+use crate::plan::{community_plugins_file, plugins_dir};
+use crate::report::{FileOutcome, FileReport, SyncReport};
+use crate::structs::vault_id;
+use crate::trash::{Retention, Trash};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    /// Hex BLAKE3 hash of the contents, the key in the [`ObjectStore`].
+    pub hash: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: String,
+    pub vault: PathBuf,
+    pub created: SystemTime,
+    /// Files by path relative to the vault.
+    pub files: BTreeMap<PathBuf, SnapshotFile>,
+}
+
+impl Snapshot {
+    pub fn bytes(&self) -> u64 {
+        self.files.values().map(|f| f.size).sum()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+    objects: ObjectStore,
+    trees: TreeCache,
+    /// Root of the vault trashes that rollbacks move files into.
+    trash: PathBuf,
+}
+
+impl Default for SnapshotStore {
+    fn default() -> Self {
+        Self::new(
+            get_data_dir().join("snapshots"),
+            ObjectStore::default(),
+            TreeCache::default(),
+            get_data_dir().join("trash"),
+        )
+    }
+}
+
+impl SnapshotStore {
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        objects: ObjectStore,
+        trees: TreeCache,
+        trash: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            dir: dir.into(),
+            objects,
+            trees,
+            trash: trash.into(),
+        }
+    }
+
+    /// A fresh id for the snapshots of one sync round.
+    pub fn next_id(&self) -> String {
+        let mut millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        while self.dir.join(millis.to_string()).exists() {
+            millis += 1;
+        }
+        millis.to_string()
+    }
+
+    /// Snapshots the plugins and community plugins list of `vault` under
+    /// `id`. Only files whose contents are not stored yet are read.
+    #[instrument(skip(self))]
+    pub fn take(&self, vault: &Path, id: &str) -> Result<Snapshot> {
+        check_id(id)?;
+        let mut files = BTreeMap::new();
+        let plugins = plugins_dir(vault);
+        let tree = self.trees.tree_for(&plugins)?;
+        let rel_plugins = plugins.strip_prefix(vault)?;
+        for rel in tree.files() {
+            let node = tree.get(&rel).expect("listed by the tree");
+            let path = plugins.join(&rel);
+            let hash = match node.hash() {
+                hash if self.objects.contains(&hash) => hash,
+                _ => self.objects.insert_file(&path)?,
+            };
+            files.insert(
+                rel_plugins.join(rel),
+                SnapshotFile {
+                    hash: blake3::Hash::from(hash).to_hex().to_string(),
+                    size: node.file_size().unwrap_or_default(),
+                },
+            );
+        }
+        let community = community_plugins_file(vault);
+        if community.exists() {
+            let hash = self.objects.insert_file(&community)?;
+            files.insert(
+                community.strip_prefix(vault)?.to_path_buf(),
+                SnapshotFile {
+                    hash: blake3::Hash::from(hash).to_hex().to_string(),
+                    size: fs::metadata(&community)?.len(),
+                },
+            );
+        }
+
+        let snapshot = Snapshot {
+            id: id.to_owned(),
+            vault: vault.to_path_buf(),
+            created: SystemTime::now(),
+            files,
+        };
+        write_atomic(&self.manifest_path(id, vault), |out| {
+            serde_json::to_writer_pretty(out, &snapshot)?;
+            Ok(())
+        })?;
+        info!(
+            files = snapshot.files.len(),
+            bytes = snapshot.bytes(),
+            "Took snapshot"
+        );
+        Ok(snapshot)
+    }
+
+    /// Every snapshot of every vault, oldest first.
+    pub fn list(&self) -> Result<Vec<Snapshot>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut snapshots = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                snapshots.extend(self.read_round(&entry.path())?);
+            }
+        }
+        snapshots.sort_by(|a, b| a.created.cmp(&b.created).then(a.vault.cmp(&b.vault)));
+        Ok(snapshots)
+    }
+
+    /// The snapshots taken under `id`, one per vault.
+    pub fn round(&self, id: &str) -> Result<Vec<Snapshot>> {
+        check_id(id)?;
+        let dir = self.dir.join(id);
+        if !dir.is_dir() {
+            return Err(eyre!("no snapshot {id}"));
+        }
+        self.read_round(&dir)
+    }
+
+    /// Removes rounds older than the retention period, then the oldest rounds
+    /// beyond the retention count. Their objects stay stored until
+    /// nothing else uses them either.
+    pub fn prune(&self, retention: &Retention) -> Result<()> {
+        let mut rounds: BTreeMap<String, SystemTime> = BTreeMap::new();
+        for snapshot in self.list()? {
+            rounds
+                .entry(snapshot.id)
+                .and_modify(|created| *created = (*created).min(snapshot.created))
+                .or_insert(snapshot.created);
+        }
+        let mut rounds = rounds.into_iter().collect::<Vec<_>>();
+        rounds.sort_by_key(|(_, created)| *created);
+        let keep_from = rounds.len().saturating_sub(retention.max_syncs);
+        let now = SystemTime::now();
+        for (i, (id, created)) in rounds.iter().enumerate() {
+            let expired = now
+                .duration_since(*created)
+                .is_ok_and(|age| age > retention.max_age);
+            if i < keep_from || expired {
+                debug!(id, "Pruning snapshot round");
+                fs::remove_dir_all(self.dir.join(id))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_round(&self, dir: &Path) -> Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(snapshot) => snapshots.push(snapshot),
+                Err(e) => warn!(snapshot = ?path, "Ignoring unreadable snapshot: {}", e),
+            }
+        }
+        Ok(snapshots)
+    }
+
+    /// Puts the plugins of `vault` back the way they were in snapshot `id`.
+    /// Files that differ are replaced and plugin files the snapshot does not
+    /// have are removed; both go to the vault's [`Trash`] first, so the
+    /// rollback itself can be undone with [`Trash::restore`].
+    #[instrument(skip(self))]
+    pub fn rollback(&self, vault: &Path, id: &str) -> Result<SyncReport> {
+        check_id(id)?;
+        let snapshot: Snapshot = match fs::read(self.manifest_path(id, vault)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(eyre!("no snapshot {id} of {}", vault.display()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let started = Instant::now();
+        let trash = Trash::new(&self.trash, vault);
+        let mut batch = trash.begin()?;
+        let mut report = SyncReport::new(self.manifest_path(id, vault), vault.to_path_buf());
+
+        for (rel, file) in &snapshot.files {
+            let target = vault.join(rel);
+            let hash = *blake3::Hash::from_hex(&file.hash)?.as_bytes();
+            let outcome = if !target.exists() {
+                FileOutcome::Created
+            } else if hash_file(&target)? == hash {
+                report.record(FileReport {
+                    bytes: file.size,
+                    ..FileReport::new(target, FileOutcome::Unchanged)
+                });
+                continue;
+            } else {
+                batch.keep(&target)?;
+                FileOutcome::Patched
+            };
+            self.objects.restore_to(&hash, &target)?;
+            debug!(file = ?target, "Rolled back file");
+            report.record(FileReport {
+                bytes: file.size,
+                bytes_written: file.size,
+                ..FileReport::new(target, outcome)
+            });
+        }
+
+        let plugins = plugins_dir(vault);
+        let mut current: BTreeSet<PathBuf> = self
+            .trees
+            .tree_for(&plugins)?
+            .files()
+            .into_iter()
+            .map(|rel| plugins.join(rel))
+            .collect();
+        current.insert(community_plugins_file(vault));
+        for target in current {
+            let rel = target.strip_prefix(vault)?;
+            if snapshot.files.contains_key(rel) || !target.exists() {
+                continue;
+            }
+            let bytes = fs::metadata(&target)?.len();
+            batch.take(&target)?;
+            debug!(file = ?target, "Removed file not in snapshot");
+            report.record(FileReport {
+                bytes,
+                ..FileReport::new(target, FileOutcome::Deleted)
+            });
+        }
+
+        if !batch.is_empty() {
+            report.trash = Some(batch.sync_id().to_owned());
+        }
+        report.duration = started.elapsed();
+        info!(report = %report, "Rolled back vault");
+        Ok(report)
+    }
+
+    fn manifest_path(&self, id: &str, vault: &Path) -> PathBuf {
+        let vault_id = vault_id(vault);
+        self.dir.join(id).join(format!("{vault_id:016x}.json"))
+    }
+}
+
+/// Snapshot ids are millisecond timestamps, like sync ids; anything else
+/// could name a path outside the store.
+fn check_id(id: &str) -> Result<()> {
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(eyre!("{id:?} is not a snapshot id"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rollback_restores_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SnapshotStore::new(
+            dir.path().join("snapshots"),
+            ObjectStore::new(dir.path().join("objects")),
+            TreeCache::new(dir.path().join("trees")),
+            dir.path().join("trash"),
+        );
+        let vault = fs::canonicalize(dir.path()).unwrap().join("vault");
+        let plugin = plugins_dir(&vault).join("calendar");
+        fs::create_dir_all(&plugin).unwrap();
+        fs::write(plugin.join("main.js"), "v1").unwrap();
+        fs::write(plugin.join("styles.css"), "body {}").unwrap();
+        fs::write(community_plugins_file(&vault), r#"["calendar"]"#).unwrap();
+
+        let id = store.next_id();
+        let snapshot = store.take(&vault, &id).unwrap();
+        assert_eq!(snapshot.files.len(), 3);
+        // the same contents again only add a manifest
+        let again = store.next_id();
+        store.take(&vault, &again).unwrap();
+        assert_eq!(store.list().unwrap().len(), 2);
+
+        fs::write(plugin.join("main.js"), "v2").unwrap();
+        fs::remove_file(plugin.join("styles.css")).unwrap();
+        fs::write(plugin.join("data.json"), "{}").unwrap();
+
+        // a trailing slash names the same vault
+        let report = store.rollback(&vault.join(""), &id).unwrap();
+        assert_eq!(report.count(FileOutcome::Patched), 1);
+        assert_eq!(report.count(FileOutcome::Created), 1);
+        assert_eq!(report.count(FileOutcome::Deleted), 1);
+        assert_eq!(report.count(FileOutcome::Unchanged), 1);
+        assert_eq!(fs::read(plugin.join("main.js")).unwrap(), b"v1");
+        assert_eq!(fs::read(plugin.join("styles.css")).unwrap(), b"body {}");
+        assert!(!plugin.join("data.json").exists());
+        assert_eq!(store.round(&id).unwrap(), vec![snapshot]);
+        assert!(store.rollback(&vault, "1").is_err());
+        for bad in ["", "..", "../snapshots", "1/.."] {
+            assert!(store.round(bad).is_err(), "{bad:?}");
+            assert!(store.rollback(&vault, bad).is_err(), "{bad:?}");
+        }
+
+        // the rollback went through the trash and can itself be undone
+        let trash = Trash::new(dir.path().join("trash"), &vault);
+        trash.restore(report.trash.as_deref().unwrap()).unwrap();
+        assert_eq!(fs::read(plugin.join("main.js")).unwrap(), b"v2");
+        assert!(plugin.join("data.json").exists());
+    }
+
+    #[test]
+    fn test_prune_drops_oldest_rounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SnapshotStore::new(
+            dir.path().join("snapshots"),
+            ObjectStore::new(dir.path().join("objects")),
+            TreeCache::new(dir.path().join("trees")),
+            dir.path().join("trash"),
+        );
+        let vault = dir.path().join("vault");
+        fs::create_dir_all(plugins_dir(&vault).join("calendar")).unwrap();
+        for id in ["1", "2", "3"] {
+            store.take(&vault, id).unwrap();
+        }
+
+        store
+            .prune(&Retention {
+                max_syncs: 2,
+                ..Retention::default()
+            })
+            .unwrap();
+        let ids = store
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["2", "3"]);
+
+        store
+            .prune(&Retention {
+                max_age: Duration::ZERO,
+                ..Retention::default()
+            })
+            .unwrap();
+        assert!(store.list().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,290 @@
+//! What every vault looked like after it was last synced.
+//!
+//! For each vault, `state/<vault id>.json` in the data dir maps the path of
+//! every synced file, relative to the vault, to the hash and modification
+//! time it had after the sync and the vault its contents came from. With it,
+//! a file that was edited since the last sync can be told apart from one
+//! that is merely out of date.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::atomic::write_atomic;
+use crate::logging::get_data_dir;
+use crate::merge::is_settings;
+use crate::merkle::hash_file;
+use crate::objects::ObjectStore;
+use crate::report::{FileOutcome, SyncReport};
+use crate::structs::vault_id;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    /// Hex BLAKE3 hash of the contents after the last sync.
+    pub hash: String,
+    pub size: u64,
+    pub mtime: SystemTime,
+    /// The vault the contents were synced from.
+    pub origin: PathBuf,
+    pub synced: SystemTime,
+}
+
+impl FileState {
+    /// Whether `metadata` still matches the file as it was last synced, in
+    /// which case its hash is still known without reading it.
+    pub fn matches(&self, metadata: &fs::Metadata) -> bool {
+        metadata.len() == self.size && metadata.modified().ok() == Some(self.mtime)
+    }
+}
+
+/// How a file compares to the last time it was synced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    /// The file was never synced.
+    Untracked,
+    /// The file still has the contents it was last synced with.
+    Synced,
+    /// The file was edited in this vault since it was last synced.
+    Changed,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VaultState {
+    pub vault: PathBuf,
+    pub files: BTreeMap<PathBuf, FileState>,
+}
+
+impl VaultState {
+    pub fn get(&self, rel: &Path) -> Option<&FileState> {
+        self.files.get(rel)
+    }
+
+    /// Status of the file at `rel` given the hash of its current contents.
+    pub fn status(&self, rel: &Path, hash: &[u8; 32]) -> FileStatus {
+        match self.files.get(rel) {
+            None => FileStatus::Untracked,
+            Some(state) if state.hash == blake3::Hash::from(*hash).to_hex().as_str() => {
+                FileStatus::Synced
+            }
+            Some(_) => FileStatus::Changed,
+        }
+    }
+
+    /// Records the file at `rel` as it is on disk now.
+    fn record(&mut self, rel: &Path, origin: &Path, synced: SystemTime) -> Result<()> {
+        let path = self.vault.join(rel);
+        let metadata = fs::metadata(&path)?;
+        let hash = match self.files.get(rel) {
+            Some(state) if state.matches(&metadata) => state.hash.clone(),
+            _ => blake3::Hash::from(hash_file(&path)?).to_hex().to_string(),
+        };
+        self.files.insert(
+            rel.to_path_buf(),
+            FileState {
+                hash,
+                size: metadata.len(),
+                mtime: metadata.modified()?,
+                origin: origin.to_path_buf(),
+                synced,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Vault states, plus the synced contents of plugin settings in an
+/// [`ObjectStore`] so they can be merged later.
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    dir: PathBuf,
+    objects: ObjectStore,
+}
+
+impl Default for StateStore {
+    fn default() -> Self {
+        Self::new(get_data_dir().join("state"), ObjectStore::default())
+    }
+}
+
+impl StateStore {
+    pub fn new(dir: impl Into<PathBuf>, objects: ObjectStore) -> Self {
+        Self {
+            dir: dir.into(),
+            objects,
+        }
+    }
+
+    /// The state of `vault`, empty if it was never synced.
+    pub fn load(&self, vault: &Path) -> Result<VaultState> {
+        match fs::read(self.path_of(vault)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(VaultState {
+                vault: vault.to_path_buf(),
+                files: BTreeMap::new(),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The state of every vault that was synced.
+    pub fn list(&self) -> Result<Vec<VaultState>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut states = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(state) => states.push(state),
+                Err(e) => warn!(state = ?path, "Ignoring unreadable state: {}", e),
+            }
+        }
+        Ok(states)
+    }
+
+    pub fn save(&self, state: &VaultState) -> Result<()> {
+        write_atomic(&self.path_of(&state.vault), |out| {
+            serde_json::to_writer(out, state)?;
+            Ok(())
+        })
+    }
+
+    /// Updates the state of both vaults of a finished sync. Files that ended
+    /// up identical in both are recorded with the source vault as their
+    /// origin, deleted files are forgotten, and files that failed or were
+    /// kept because only the destination changed them are left as they were.
+    pub fn record(&self, report: &SyncReport) -> Result<()> {
+        let mut from = self.load(&report.from)?;
+        let mut to = self.load(&report.to)?;
+        for file in &report.files {
+            let rel = file.path.strip_prefix(&report.to)?;
+            match file.outcome {
+                FileOutcome::Created
+                | FileOutcome::Patched
+                | FileOutcome::Merged
+                | FileOutcome::Unchanged => {
+                    for state in [&mut from, &mut to] {
+                        if let Err(e) = state.record(rel, &report.from, report.started) {
+                            warn!(
+                                vault = ?state.vault,
+                                file = ?rel,
+                                "Could not record file state: {}",
+                                e
+                            );
+                            state.files.remove(rel);
+                        }
+                    }
+                    if is_settings(rel)
+                        && to.get(rel).is_some()
+                        && let Err(e) = self.keep_contents(&to, rel)
+                    {
+                        warn!(file = ?rel, "Could not keep synced settings: {}", e);
+                    }
+                }
+                FileOutcome::Deleted => {
+                    from.files.remove(rel);
+                    to.files.remove(rel);
+                }
+                FileOutcome::Failed | FileOutcome::Kept | FileOutcome::Conflict => {}
+            }
+        }
+        self.save(&from)?;
+        self.save(&to)?;
+        debug!(from = ?report.from, to = ?report.to, "Recorded sync state");
+        Ok(())
+    }
+
+    /// Stores the contents of `rel` in `state`'s vault unless its recorded
+    /// hash is already stored.
+    fn keep_contents(&self, state: &VaultState, rel: &Path) -> Result<()> {
+        let stored = state
+            .get(rel)
+            .and_then(|s| blake3::Hash::from_hex(&s.hash).ok())
+            .is_some_and(|hash| self.objects.contains(hash.as_bytes()));
+        if !stored {
+            self.objects.insert_file(&state.vault.join(rel))?;
+        }
+        Ok(())
+    }
+
+    fn path_of(&self, vault: &Path) -> PathBuf {
+        let id = vault_id(vault);
+        self.dir.join(format!("{id:016x}.json"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::FileReport;
+
+    #[test]
+    fn test_state_tells_changed_from_synced() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("main");
+        let to = dir.path().join("work");
+        for vault in [&from, &to] {
+            fs::create_dir_all(vault.join("calendar")).unwrap();
+            fs::write(vault.join("calendar/main.js"), "v1").unwrap();
+        }
+        fs::write(to.join("calendar/data.json"), "{}").unwrap();
+
+        let mut report = SyncReport::new(from.clone(), to.clone());
+        report.record(FileReport::new(
+            to.join("calendar/main.js"),
+            FileOutcome::Patched,
+        ));
+        report.record(FileReport::new(
+            to.join("calendar/data.json"),
+            FileOutcome::Failed,
+        ));
+        let store = StateStore::new(
+            dir.path().join("state"),
+            ObjectStore::new(dir.path().join("objects")),
+        );
+        store.record(&report).unwrap();
+
+        // a new store reads the same state back
+        let store = StateStore::new(
+            dir.path().join("state"),
+            ObjectStore::new(dir.path().join("objects")),
+        );
+        let state = store.load(&to).unwrap();
+        let rel = Path::new("calendar/main.js");
+        assert_eq!(state.get(rel).unwrap().origin, from);
+        assert_eq!(
+            state.status(rel, blake3::hash(b"v1").as_bytes()),
+            FileStatus::Synced
+        );
+        assert_eq!(
+            state.status(rel, blake3::hash(b"v2").as_bytes()),
+            FileStatus::Changed
+        );
+        assert_eq!(
+            state.status(Path::new("calendar/data.json"), &[0; 32]),
+            FileStatus::Untracked
+        );
+        assert_eq!(store.load(&from).unwrap().files.len(), 1);
+        assert_eq!(store.list().unwrap().len(), 2);
+
+        let mut report = SyncReport::new(from.clone(), to.clone());
+        report.record(FileReport::new(
+            to.join("calendar/main.js"),
+            FileOutcome::Deleted,
+        ));
+        store.record(&report).unwrap();
+        assert!(store.load(&to).unwrap().files.is_empty());
+    }
+}
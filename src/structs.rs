@@ -1,8 +1,14 @@
 use color_eyre::eyre::{Context, Result};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf, sync::LazyLock};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
 use tracing::{error, info};
+use xxhash_rust::xxh3::xxh3_64;
 
 pub static VAULTS_FILE: LazyLock<PathBuf> = LazyLock::new(|| {
     let obsidian_dir_name = {
@@ -26,6 +32,14 @@ pub static VAULTS_FILE: LazyLock<PathBuf> = LazyLock::new(|| {
     }
 });
 
+/// The key `vault` is stored under in the data dir. The path is resolved
+/// first, so a relative path, a trailing slash or a symlink finds the same
+/// trash, state and snapshots as the path Obsidian lists.
+pub fn vault_id(vault: &Path) -> u64 {
+    let path = fs::canonicalize(vault).unwrap_or_else(|_| vault.components().collect());
+    xxh3_64(path.as_os_str().as_encoded_bytes())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct Vault {
     pub path: PathBuf,
@@ -57,8 +71,8 @@ impl Vaults {
     }
     pub fn get_open_vaults(&self) -> Vec<Vault> {
         self.vaults
-            .iter()
-            .flat_map(|(_, v)| {
+            .values()
+            .flat_map(|v| {
                 if v.open.unwrap_or(false) {
                     Some(v.clone())
                 } else {
@@ -80,4 +94,10 @@ pub enum Action {
     UpdatePlugins(PathBuf),
     StartedSync,
     FinishedSync,
+    /// A plugin file of `vault` changed there and in another vault since they
+    /// were last synced, so it was not overwritten.
+    Conflict {
+        vault: PathBuf,
+        file: PathBuf,
+    },
 }
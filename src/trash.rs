@@ -0,0 +1,362 @@
+//! Recoverable trash for files that syncing deletes or overwrites.
+//!
+//! Every vault has its own trash under `trash` in the data dir. Each sync
+//! that removes or replaces files gets a batch in it, named by its sync id,
+//! holding the old files at their paths relative to the vault and a
+//! `manifest.json` listing them. [`Trash::restore`] puts a batch back, and
+//! [`Trash::prune`] drops batches beyond the [`Retention`] limits.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::{Result, eyre};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::atomic::write_atomic;
+use crate::logging::get_data_dir;
+use crate::structs::vault_id;
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrashReason {
+    /// The sync deleted the file.
+    Deleted,
+    /// The sync overwrote the file with new contents.
+    Replaced,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrashEntry {
+    /// Path relative to the vault.
+    pub path: PathBuf,
+    pub reason: TrashReason,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrashManifest {
+    pub vault: PathBuf,
+    pub sync_id: String,
+    pub created: SystemTime,
+    pub entries: Vec<TrashEntry>,
+}
+
+/// How many sync batches, of a vault's trash or of snapshots, are kept and
+/// for how long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub max_syncs: usize,
+    pub max_age: Duration,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_syncs: 50,
+            max_age: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// The trash of one vault.
+#[derive(Debug, Clone)]
+pub struct Trash {
+    dir: PathBuf,
+    vault: PathBuf,
+}
+
+impl Trash {
+    pub fn for_vault(vault: &Path) -> Self {
+        Self::new(get_data_dir().join("trash"), vault)
+    }
+
+    /// Trash of `vault` kept under `root`.
+    pub fn new(root: impl Into<PathBuf>, vault: &Path) -> Self {
+        let id = vault_id(vault);
+        Self {
+            dir: root.into().join(format!("{id:016x}")),
+            vault: vault.to_path_buf(),
+        }
+    }
+
+    /// Starts the batch for one sync. Its directory is created right away,
+    /// which claims the sync id, and removed again if the batch stays empty.
+    pub fn begin(&self) -> Result<TrashBatch> {
+        fs::create_dir_all(&self.dir)?;
+        let now = SystemTime::now();
+        let mut millis = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // two syncs of the same vault within a millisecond get distinct ids
+        let dir = loop {
+            let dir = self.dir.join(millis.to_string());
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => millis += 1,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        Ok(TrashBatch {
+            dir,
+            manifest: TrashManifest {
+                vault: self.vault.clone(),
+                sync_id: millis.to_string(),
+                created: now,
+                entries: Vec::new(),
+            },
+        })
+    }
+
+    /// Manifests of every batch, oldest first.
+    pub fn list(&self) -> Result<Vec<TrashManifest>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut manifests = Vec::new();
+        for entry in entries {
+            let path = entry?.path().join(MANIFEST_FILE);
+            match fs::read(&path).map(|bytes| serde_json::from_slice::<TrashManifest>(&bytes)) {
+                Ok(Ok(manifest)) => manifests.push(manifest),
+                Ok(Err(e)) => warn!(manifest = ?path, "Ignoring unreadable trash manifest: {}", e),
+                // a batch that nothing was put in yet
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => warn!(manifest = ?path, "Ignoring trash batch without manifest: {}", e),
+            }
+        }
+        manifests.sort_by_key(|m| m.created);
+        Ok(manifests)
+    }
+
+    /// Puts every file of the sync `sync_id` back where it was, overwriting
+    /// what is there now. Files the sync created are left alone. Returns the
+    /// restored paths.
+    pub fn restore(&self, sync_id: &str) -> Result<Vec<PathBuf>> {
+        // sync ids are millisecond timestamps; anything else could name a
+        // path outside the trash
+        if sync_id.is_empty() || !sync_id.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(eyre!("{sync_id:?} is not a sync id"));
+        }
+        let dir = self.dir.join(sync_id);
+        let manifest: TrashManifest = match fs::read(dir.join(MANIFEST_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(eyre!(
+                    "no trashed files from sync {sync_id} of {}",
+                    self.vault.display()
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut restored = Vec::new();
+        for entry in &manifest.entries {
+            let target = self.vault.join(&entry.path);
+            let mut trashed = fs::File::open(dir.join(&entry.path))?;
+            write_atomic(&target, |out| {
+                io::copy(&mut trashed, out)?;
+                Ok(())
+            })?;
+            debug!(file = ?target, "Restored file");
+            restored.push(target);
+        }
+        info!(vault = ?self.vault, sync_id, files = restored.len(), "Restored trashed files");
+        Ok(restored)
+    }
+
+    /// Removes batches older than the retention period, then the oldest
+    /// batches beyond the retention count.
+    pub fn prune(&self, retention: &Retention) -> Result<()> {
+        let manifests = self.list()?;
+        let keep_from = manifests.len().saturating_sub(retention.max_syncs);
+        let now = SystemTime::now();
+        for (i, manifest) in manifests.iter().enumerate() {
+            let expired = now
+                .duration_since(manifest.created)
+                .is_ok_and(|age| age > retention.max_age);
+            if i < keep_from || expired {
+                debug!(sync_id = manifest.sync_id, "Pruning trash batch");
+                fs::remove_dir_all(self.dir.join(&manifest.sync_id))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The files one sync moved to the trash.
+#[derive(Debug)]
+pub struct TrashBatch {
+    dir: PathBuf,
+    manifest: TrashManifest,
+}
+
+impl TrashBatch {
+    pub fn sync_id(&self) -> &str {
+        &self.manifest.sync_id
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.manifest.entries.is_empty()
+    }
+
+    /// Moves `path` into the trash before the sync deletes it.
+    pub fn take(&mut self, path: &Path) -> Result<()> {
+        let dest = self.prepare(path)?;
+        if fs::rename(path, &dest).is_err() {
+            // the data dir may be on another filesystem
+            fs::copy(path, &dest)?;
+            fs::remove_file(path)?;
+        }
+        self.record(path, dest, TrashReason::Deleted)
+    }
+
+    /// Copies `path` into the trash before the sync overwrites it.
+    pub fn keep(&mut self, path: &Path) -> Result<()> {
+        let dest = self.prepare(path)?;
+        fs::copy(path, &dest)?;
+        self.record(path, dest, TrashReason::Replaced)
+    }
+
+    fn prepare(&self, path: &Path) -> Result<PathBuf> {
+        let rel = self.relative(path)?;
+        let dest = self.dir.join(rel);
+        fs::create_dir_all(dest.parent().expect("joined onto the batch dir"))?;
+        Ok(dest)
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> Result<&'a Path> {
+        path.strip_prefix(&self.manifest.vault).map_err(|_| {
+            eyre!(
+                "{} is not in vault {}",
+                path.display(),
+                self.manifest.vault.display()
+            )
+        })
+    }
+
+    fn record(&mut self, path: &Path, dest: PathBuf, reason: TrashReason) -> Result<()> {
+        self.manifest.entries.push(TrashEntry {
+            path: self.relative(path)?.to_path_buf(),
+            reason,
+            size: fs::metadata(dest)?.len(),
+        });
+        // written after every file so a crash mid-sync loses nothing
+        write_atomic(&self.dir.join(MANIFEST_FILE), |out| {
+            serde_json::to_writer_pretty(out, &self.manifest)?;
+            Ok(())
+        })
+    }
+}
+
+impl Drop for TrashBatch {
+    fn drop(&mut self) {
+        if self.is_empty() {
+            let _ = fs::remove_dir(&self.dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_trashed_files_can_be_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = dir.path().join("vault");
+        let plugin = vault.join(".obsidian").join("plugins").join("calendar");
+        fs::create_dir_all(&plugin).unwrap();
+        fs::write(plugin.join("main.js"), "old main").unwrap();
+        fs::write(plugin.join("data.json"), "old data").unwrap();
+
+        let trash = Trash::new(dir.path().join("trash"), &vault);
+        let mut batch = trash.begin().unwrap();
+        batch.take(&plugin.join("main.js")).unwrap();
+        batch.keep(&plugin.join("data.json")).unwrap();
+        fs::write(plugin.join("data.json"), "new data").unwrap();
+        assert!(!plugin.join("main.js").exists());
+
+        let manifests = trash.list().unwrap();
+        assert_eq!(manifests.len(), 1);
+        assert_eq!(manifests[0].sync_id, batch.sync_id());
+        assert_eq!(
+            manifests[0].entries[0],
+            TrashEntry {
+                path: PathBuf::from(".obsidian/plugins/calendar/main.js"),
+                reason: TrashReason::Deleted,
+                size: 8
+            }
+        );
+
+        // the same vault given with a trailing slash has the same trash
+        let slashed = Trash::new(dir.path().join("trash"), &dir.path().join("vault").join(""));
+        assert_eq!(slashed.list().unwrap(), manifests);
+
+        let restored = trash.restore(batch.sync_id()).unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(fs::read(plugin.join("main.js")).unwrap(), b"old main");
+        assert_eq!(fs::read(plugin.join("data.json")).unwrap(), b"old data");
+        assert!(trash.restore("0").is_err());
+        for bad in ["", "..", "../..", "1/../.."] {
+            assert!(trash.restore(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn test_batches_never_share_an_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let trash = Trash::new(dir.path().join("trash"), &dir.path().join("vault"));
+        let batches: Vec<TrashBatch> = (0..5).map(|_| trash.begin().unwrap()).collect();
+        let ids: HashSet<&str> = batches.iter().map(TrashBatch::sync_id).collect();
+        assert_eq!(ids.len(), batches.len());
+        assert!(trash.list().unwrap().is_empty());
+
+        // empty batches leave nothing behind
+        drop(batches);
+        assert_eq!(fs::read_dir(&trash.dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_prune_keeps_newest_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = dir.path().join("vault");
+        fs::create_dir_all(&vault).unwrap();
+        let trash = Trash::new(dir.path().join("trash"), &vault);
+        let mut ids = Vec::new();
+        for i in 0..3 {
+            let file = vault.join(format!("{i}.js"));
+            fs::write(&file, "x").unwrap();
+            let mut batch = trash.begin().unwrap();
+            batch.take(&file).unwrap();
+            ids.push(batch.sync_id().to_owned());
+        }
+
+        let retention = Retention {
+            max_syncs: 2,
+            ..Retention::default()
+        };
+        trash.prune(&retention).unwrap();
+        let kept: Vec<String> = trash
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|m| m.sync_id)
+            .collect();
+        assert_eq!(kept, ids[1..]);
+
+        trash
+            .prune(&Retention {
+                max_syncs: 10,
+                max_age: Duration::ZERO,
+            })
+            .unwrap();
+        assert!(trash.list().unwrap().is_empty());
+    }
+}